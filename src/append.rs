@@ -0,0 +1,251 @@
+use std::{fs::File, io::{self, Seek, SeekFrom, Write}, path::{Path, PathBuf}, thread};
+
+use crate::{archive::{sub_archive::{DataLocation, SubArchive}, Archive}, crypto::{generate_iv, EncryptWriter, Key, IV}, group::Group, output::Output, pack::{collect_warnings, encoder_threads, index_sources, pack_group, pack_source_groups, write_source_groups, PackContext, PackOptions, SourceGroup}, progress::ProgressDisplay, segment::{Segment, SegmentWriter}, warning::{print_summary, Warning}, BKY_HEADER, BKY_HEADER_APPENDED, END_MARKER};
+
+/// Adds the sources as new source groups to an existing archive, leaving the data already packed untouched
+///
+/// The source groups are written after the end of a single file archive, followed by a new header encrypted with a new IV.
+/// A split archive gets a new file containing the source groups.
+/// Returns the files which couldn't be packed completely if errors are skipped, or which changed while they were read.
+pub fn append(archive: PathBuf, sources: Vec<PathBuf>, key: Key, options: PackOptions) -> Result<Vec<Warning>, io::Error> {
+	if options.max_group_size.is_some() {
+		panic!("max_group_size can't be used when appending, a split archive gets a single new file");
+	}
+	
+	if !options.incremental_from.is_empty() {
+		panic!("incremental_from can't be used when appending");
+	}
+	
+	if options.deterministic_iv {
+		panic!("deterministic_iv can't be used when appending, the IV of the archive is already chosen");
+	}
+	
+	if !archive.exists() {
+		return Err(io::Error::new(io::ErrorKind::NotFound, format!("archive {} doesn't exist", archive.display())));
+	}
+	
+	let (group, _) = index_sources(sources, key, &options)?;
+	
+	let existing_sources = Archive::new(archive.clone(), key).sources()?;
+	
+	if let Some(entry) = group.entries.iter().find(|entry| existing_sources.iter().any(|id| *id == *entry.source.id)) {
+		return Err(io::Error::new(
+			io::ErrorKind::AlreadyExists,
+			format!("source {} is already contained in the archive", entry.source.id)
+		));
+	}
+	
+	let available_threads = thread::available_parallelism()
+		.map(|threads| threads.get() as u32)
+		.unwrap_or(1);
+	let threads = options.threads.unwrap_or(available_threads);
+	
+	let warnings = if archive.is_dir() {
+		append_volume(&archive, group, key, &options, threads)?
+	} else {
+		append_to_file(&archive, group, key, &options, threads)?
+	};
+	
+	print_summary(&warnings);
+	
+	Ok(warnings)
+}
+
+/// Packs the group into a new file of a split archive and marks the existing files as containing multiple sources
+fn append_volume(archive: &Path, group: Group, key: Key, options: &PackOptions, threads: u32) -> Result<Vec<Warning>, io::Error> {
+	let volume_paths = Archive::new(archive.to_owned(), key).volume_paths()?;
+	
+	let number = volume_paths.iter()
+		.filter_map(|path| path.file_stem()?.to_str()?.parse::<u64>().ok())
+		.max()
+		.unwrap_or(0) + 1;
+	let path = archive.join(format!("{number}.bky"));
+	
+	let output = Output::new(false);
+	let progress_display = ProgressDisplay::new(group.size);
+	let progress_tracker = progress_display.new_tracker(path.to_string_lossy().into_owned(), group.size);
+	let context = PackContext {
+		output: &output,
+		key,
+		options,
+		threads,
+		is_single_source: false,
+	};
+	let warnings = pack_group(&context, &path, group, progress_tracker)?;
+	output.finish()?;
+	
+	// the existing files are only changed once the new file is complete
+	for path in volume_paths {
+		let mut file = File::options().read(true).write(true).open(path)?;
+		let sub_archive = SubArchive::new(&file, key)?;
+		let flags = sub_archive.flags();
+		let iv = sub_archive.iv();
+		
+		if flags & 1 != 0 {
+			write_header_start(&mut file, key, iv, &(flags & !1).to_le_bytes())?;
+		}
+	}
+	
+	Ok(warnings)
+}
+
+/// Packs the group after the end of a single file archive, followed by the header of all source groups
+///
+/// The appended data is encrypted with a new IV stored after the header, so an append which failed and was undone
+/// never shares a keystream with the data packed again. The data already in the archive, including the header of
+/// previous appends, is left untouched and skipped when reading, as every source group stores where its data starts.
+/// The archive is restored to its previous size if packing fails or is interrupted.
+fn append_to_file(archive: &Path, group: Group, key: Key, options: &PackOptions, threads: u32) -> Result<Vec<Warning>, io::Error> {
+	let mut file = File::options().read(true).write(true).open(archive)?;
+	let mut sub_archive = SubArchive::new(&file, key)?;
+	
+	if !sub_archive.is_segmented() {
+		return Err(io::Error::new(
+			io::ErrorKind::Unsupported,
+			"sources can only be appended to archives whose source groups are split into segments"
+		));
+	}
+	
+	let compression = sub_archive.compression();
+	let iv = sub_archive.iv();
+	let file_len = file.metadata()?.len();
+	
+	// the data of the groups packed with the archive follows its header, the groups of previous appends store their location
+	let mut position = sub_archive.data_start()?;
+	let mut source_groups: Vec<SourceGroup> = sub_archive.into_source_groups()
+		.into_iter()
+		.map(|group| {
+			let location = group.location.unwrap_or(DataLocation {
+				iv,
+				start: position,
+			});
+			position = location.start + compressed_size(&group.segments);
+			
+			let mut source_group = SourceGroup::from(group);
+			source_group.location = Some(location);
+			source_group
+		})
+		.collect();
+	
+	let progress_display = ProgressDisplay::new(group.size);
+	let progress_tracker = progress_display.new_tracker("Total", group.size);
+	
+	let output = Output::new(false);
+	output.restore_len(archive, file_len);
+	
+	let mut new_groups = Vec::new();
+	SourceGroup::group_by_source(&mut new_groups, group, options)?;
+	
+	// the keystream of every IV starts after the IV of the archive, so the locations are positions in the file
+	let append_iv = generate_iv();
+	file.seek(SeekFrom::Start((BKY_HEADER.len() + size_of::<IV>()) as u64))?;
+	let mut encrypter = EncryptWriter::new(&mut file, key, append_iv);
+	encrypter.seek(SeekFrom::Start(file_len))?;
+	
+	let mut encrypter = pack_source_groups(
+		encrypter,
+		&mut new_groups,
+		options,
+		encoder_threads(threads, options),
+		&progress_tracker,
+		SegmentWriter::discard_last_segment
+	)?;
+	let warnings = collect_warnings(&new_groups);
+	
+	let mut position = file_len;
+	
+	for group in &mut new_groups {
+		group.location = Some(DataLocation {
+			iv: append_iv,
+			start: position,
+		});
+		position += compressed_size(&group.segments);
+	}
+	
+	source_groups.append(&mut new_groups);
+	
+	// the archive is only segmented, its header is neither padded nor stored at its start
+	let mut header = 2u32.to_le_bytes().to_vec();
+	header.push(compression.to_byte());
+	write_source_groups(&mut header, &source_groups)?;
+	let header_len: u64 = header.len() as u64;
+	
+	encrypter.write_all(&header)?;
+	encrypter.write_all(&header_len.to_le_bytes())?;
+	encrypter.write_all(END_MARKER)?;
+	encrypter.flush()?;
+	
+	// segments discarded to pack changed files again might have been written past the end
+	let end = encrypter.stream_position()?;
+	let file = encrypter.into_inner();
+	file.write_all(&append_iv)?;
+	file.set_len(end + size_of::<IV>() as u64)?;
+	
+	// the archive is only restored until it is marked as appended, its previous header remains valid for the previous data
+	output.finish()?;
+	
+	file.seek(SeekFrom::Start(0))?;
+	file.write_all(BKY_HEADER_APPENDED)?;
+	file.flush()?;
+	
+	Ok(warnings)
+}
+
+fn compressed_size(segments: &[Segment]) -> u64 {
+	segments.iter()
+		.map(|segment| segment.compressed_size)
+		.sum()
+}
+
+/// Overwrites the start of the encrypted header of an archive, which starts with its flags, with a single write
+fn write_header_start(file: &mut File, key: Key, iv: IV, header_start: &[u8]) -> Result<(), io::Error> {
+	file.seek(SeekFrom::Start((BKY_HEADER.len() + size_of::<IV>()) as u64))?;
+	let mut encrypter = EncryptWriter::new(file, key, iv);
+	encrypter.write_all(header_start)?;
+	encrypter.flush()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	
+	use crate::{crypto::generate_key, pack::pack};
+	
+	use super::*;
+	
+	#[test]
+	fn appends_leave_previous_data_untouched_and_use_new_ivs() {
+		let dir = tempfile::tempdir().unwrap();
+		
+		for (source, contents) in [("first", "one"), ("second", "two"), ("third", "three")] {
+			fs::create_dir(dir.path().join(source)).unwrap();
+			fs::write(dir.path().join(source).join("file"), contents).unwrap();
+		}
+		
+		let key = generate_key();
+		let out = dir.path().join("out.bky");
+		pack(vec![dir.path().join("first")], out.clone(), key, PackOptions::default()).unwrap();
+		let packed = fs::read(&out).unwrap();
+		
+		append(out.clone(), vec![dir.path().join("second")], key, PackOptions::default()).unwrap();
+		let appended = fs::read(&out).unwrap();
+		assert_eq!(&appended[..BKY_HEADER.len()], BKY_HEADER_APPENDED);
+		assert_eq!(appended[BKY_HEADER.len()..packed.len()], packed[BKY_HEADER.len()..]);
+		
+		// appending again after the previous append was undone doesn't reuse its keystream
+		fs::write(&out, &packed).unwrap();
+		append(out.clone(), vec![dir.path().join("second")], key, PackOptions::default()).unwrap();
+		let appended_again = fs::read(&out).unwrap();
+		assert_ne!(appended_again[packed.len()..], appended[packed.len()..]);
+		
+		append(out.clone(), vec![dir.path().join("third")], key, PackOptions::default()).unwrap();
+		assert_eq!(fs::read(&out).unwrap()[BKY_HEADER.len()..appended_again.len()], appended_again[BKY_HEADER.len()..]);
+		
+		let unpacked = dir.path().join("unpacked");
+		Archive::new(out, key).unpack(unpacked.clone()).unwrap();
+		
+		for (source, contents) in [("first", "one"), ("second", "two"), ("third", "three")] {
+			assert_eq!(fs::read_to_string(unpacked.join(source).join("file")).unwrap(), contents);
+		}
+	}
+}
@@ -1,11 +1,11 @@
-use std::{borrow::Cow, fs::{self, DirEntry, File}, io::{self, Read, Seek, Write}, ops::ControlFlow, path::{Path, PathBuf}};
+use std::{borrow::Cow, fs::{self, File, Permissions}, io::{self, Read, Seek, Write}, ops::ControlFlow, os::unix::fs::PermissionsExt, path::{Component, Path, PathBuf}, sync::Mutex, time::{Duration, SystemTime, UNIX_EPOCH}};
 
 use either::Either;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
-use crate::{crypto::Key, progress::{ProgressDisplay, ProgressTracker}};
+use crate::{crypto::Key, part::Part, progress::{ProgressDisplay, ProgressTracker}, warning::Warning, Source};
 
-mod sub_archive;
+pub(crate) mod sub_archive;
 use sub_archive::SubArchive;
 
 pub struct Archive {
@@ -27,20 +27,22 @@ impl Archive {
 	}
 	
 	pub fn unpack(&self, out: PathBuf) -> Result<(), io::Error> {
+		let split_files = Mutex::new(Vec::new());
+		
 		if self.path.is_dir() {
-			let entries: Vec<DirEntry> = fs::read_dir(&self.path)?.collect::<Result<_, _>>()?;
-			let total_size: u64 = entries.iter()
-				.map(|entry| -> Result<_, io::Error> {
-					Ok(entry.metadata()?.len())
+			let paths = self.volume_paths()?;
+			let total_size: u64 = paths.iter()
+				.map(|path| -> Result<_, io::Error> {
+					Ok(path.metadata()?.len())
 				})
 				.sum::<Result<_, _>>()?;
 			
 			let progress_display = ProgressDisplay::new(total_size);
 			
-			entries.into_par_iter()
-				.map(|entry| -> Result<_, io::Error> {
-					let progress_tracker = progress_display.new_tracker(entry.path().to_string_lossy().into_owned(), entry.metadata()?.len());
-					self.unpack_group(&entry.path(), &out, progress_tracker)?;
+			paths.into_par_iter()
+				.map(|path| -> Result<_, io::Error> {
+					let progress_tracker = progress_display.new_tracker(path.to_string_lossy().into_owned(), path.metadata()?.len());
+					self.unpack_group(&path, &out, &split_files, progress_tracker)?;
 					
 					Ok(())
 				})
@@ -49,13 +51,24 @@ impl Archive {
 			let total_size = self.path.metadata()?.len();
 			let progress_display = ProgressDisplay::new(total_size);
 			let progress_tracker = progress_display.new_tracker("Total", total_size);
-			self.unpack_group(&self.path, &out, progress_tracker)?;
+			self.unpack_group(&self.path, &out, &split_files, progress_tracker)?;
+		}
+		
+		// parts are written in parallel, so the modification time can only be restored once all of them are written
+		for (path, modified) in split_files.into_inner().unwrap() {
+			File::options().write(true).open(path)?.set_modified(modified)?;
 		}
 		
 		Ok(())
 	}
 	
-	fn unpack_group(&self, group: &Path, out: &Path, progress_tracker: ProgressTracker) -> Result<(), io::Error> {
+	fn unpack_group(
+		&self,
+		group: &Path,
+		out: &Path,
+		split_files: &Mutex<Vec<(PathBuf, SystemTime)>>,
+		progress_tracker: ProgressTracker
+	) -> Result<(), io::Error> {
 		let file = File::open(group)?;
 		let sub_archive = SubArchive::new(&file, self.key)?;
 		
@@ -72,7 +85,28 @@ impl Archive {
 			};
 			
 			fs::create_dir_all(&directory)?;
-			tar.unpack(&directory)?;
+			
+			for entry in tar.entries()? {
+				let mut entry = entry?;
+				
+				match Part::read(&mut entry)? {
+					Some(part) => {
+						let modified = UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?);
+						let path = unpack_part(&mut entry, part, &directory)?;
+						split_files.lock().unwrap().push((path, modified));
+					},
+					None => {
+						entry.unpack_in(&directory)?;
+					},
+				}
+			}
+			
+			for path in &source_group.deletions {
+				match fs::remove_file(directory.join(path)) {
+					Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+					_ => (),
+				}
+			}
 			
 			progress_tracker.advance(source_group.size);
 			
@@ -83,67 +117,54 @@ impl Archive {
 	}
 	
 	pub fn sources(&self) -> Result<Vec<String>, io::Error> {
-		if self.path.is_dir() {
-			let mut sources: Vec<String> = Vec::new();
-			
-			for entry in fs::read_dir(&self.path)? {
-				let file = File::open(entry?.path())?;
-				for source in SubArchive::new(file, self.key)?.sources() {
-					if !sources.iter().any(|s| s == source) {
-						sources.push(source.to_owned());
-					}
+		let mut sources: Vec<String> = Vec::new();
+		
+		// sources can be split across multiple files or source groups
+		for sub_archive in self.sub_archives()? {
+			for source in sub_archive?.sources() {
+				if !sources.iter().any(|s| s == source) {
+					sources.push(source.to_owned());
 				}
 			}
-			
-			Ok(sources)
-		} else {
-			let file = File::open(&self.path)?;
-			Ok(SubArchive::new(file, self.key)?
-				.sources()
-				.map(ToOwned::to_owned)
-				.collect())
 		}
+		
+		Ok(sources)
 	}
 	
-	pub fn for_each_file(&self, mut callback: impl FnMut(&str, &Path)) -> Result<(), io::Error> {
+	/// Returns the files which couldn't be packed completely because errors were skipped, or which changed while they were read
+	pub fn warnings(&self) -> Result<Vec<Warning>, io::Error> {
+		let mut warnings = Vec::new();
+		
 		for sub_archive in self.sub_archives()? {
-			let sub_archive = sub_archive?;
-			
-			sub_archive.for_each_tar(|source_group, tar| {
-				let iter = tar.entries()?
-					.map(|entry| entry.map(|entry| {
-						entry.path().unwrap().into_owned()
-					}));
-				
-				for entry in iter {
-					callback(&source_group.id, &entry?);
-				}
+			for group in sub_archive?.into_source_groups() {
+				let source = Source {
+					id: group.id.as_str().into(),
+					is_file: group.flags & 1 != 0,
+					is_stdin: false,
+					path: Path::new(&group.id).into(),
+				};
 				
-				Ok(ControlFlow::Continue(()))
-			})?;
+				warnings.extend(group.warnings.into_iter().map(|(path, message)| Warning::new(source.clone(), path, message)));
+			}
 		}
 		
-		Ok(())
+		Ok(warnings)
 	}
 	
-	pub fn get_file(&self, source: Option<&str>, path: &str, mut writer: impl Write) -> Result<(), io::Error> {
+	pub fn for_each_file(&self, mut callback: impl FnMut(&str, &Path)) -> Result<(), io::Error> {
 		for sub_archive in self.sub_archives()? {
 			let sub_archive = sub_archive?;
 			
 			sub_archive.for_each_tar(|source_group, tar| {
-				if source.is_some_and(|source| source_group.id == source) {
-					return Ok(ControlFlow::Continue(()));
-				}
-				
-				if let Some(entry) =
-					tar.entries()?
-						.find(|entry| {
-							entry.as_ref().unwrap().path().unwrap().to_str().unwrap() == path
-						})
-				{
+				for entry in tar.entries()? {
 					let mut entry = entry?;
-					io::copy(&mut entry, &mut writer)?;
-					return Ok(ControlFlow::Break(()));
+					
+					// files split into parts are only listed once
+					if Part::read(&mut entry)?.is_some_and(|part| part.offset > 0) {
+						continue;
+					}
+					
+					callback(&source_group.id, &entry.path()?);
 				}
 				
 				Ok(ControlFlow::Continue(()))
@@ -153,12 +174,18 @@ impl Archive {
 		Ok(())
 	}
 	
-	fn sub_archives(&self) -> Result<impl Iterator<Item = Result<SubArchive<impl Read>, io::Error>>, io::Error> {
+	pub fn get_file(&self, source: Option<&str>, path: &str, writer: impl Write) -> Result<(), io::Error> {
+		copy_file(self.sub_archives()?, source, Path::new(path), writer)?;
+		
+		Ok(())
+	}
+	
+	pub(crate) fn sub_archives(&self) -> Result<impl Iterator<Item = Result<SubArchive<impl Read + Seek>, io::Error>>, io::Error> {
 		let iter = if self.path.is_dir() {
 			Either::Left(
-				fs::read_dir(&self.path)?
-					.map(|entry| {
-						let path = entry?.path();
+				self.volume_paths()?
+					.into_iter()
+					.map(|path| {
 						let file = File::open(&path)?;
 						SubArchive::new(file, self.key)
 					})
@@ -171,4 +198,122 @@ impl Archive {
 		
 		Ok(iter)
 	}
+	
+	/// Paths of the files of a split archive, ordered by their number
+	pub(crate) fn volume_paths(&self) -> Result<Vec<PathBuf>, io::Error> {
+		// hidden files are the temporary files and state of an unfinished pack
+		let mut paths: Vec<PathBuf> = fs::read_dir(&self.path)?
+			.map(|entry| entry.map(|entry| entry.path()))
+			.filter(|path| !path.as_ref().is_ok_and(|path| path.file_name().is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))))
+			.collect::<Result<_, _>>()?;
+		
+		paths.sort_by_cached_key(|path| {
+			let number = path.file_stem()
+				.and_then(|stem| stem.to_str())
+				.and_then(|stem| stem.parse::<u64>().ok());
+			
+			(number, path.clone())
+		});
+		
+		Ok(paths)
+	}
+}
+
+/// Writes the data of the first file at `path` found in the sub archives, joining its parts, and returns its size
+pub(crate) fn copy_file(
+	sub_archives: impl Iterator<Item = Result<SubArchive<impl Read + Seek>, io::Error>>,
+	source: Option<&str>,
+	path: &Path,
+	mut writer: impl Write
+) -> Result<u64, io::Error> {
+	let mut is_done = false;
+	// bytes written so far and size of the whole file, if the file is split into parts
+	let mut written = 0;
+	let mut total_size = None;
+	
+	for sub_archive in sub_archives {
+		let sub_archive = sub_archive?;
+		
+		sub_archive.for_each_tar(|source_group, tar| {
+			if source.is_some_and(|source| source_group.id != source) {
+				return Ok(ControlFlow::Continue(()));
+			}
+			
+			for entry in tar.entries()? {
+				let mut entry = entry?;
+				
+				if *entry.path()? != *path {
+					continue;
+				}
+				
+				let Some(part) = Part::read(&mut entry)? else {
+					written = io::copy(&mut entry, &mut writer)?;
+					is_done = true;
+					return Ok(ControlFlow::Break(()));
+				};
+				
+				if part.offset != written {
+					return Err(io::Error::new(io::ErrorKind::InvalidData, "parts of file are out of order"));
+				}
+				
+				written += io::copy(&mut entry, &mut writer)?;
+				total_size = Some(part.total_size);
+				is_done = part.total_size.is_some_and(|total_size| written >= total_size);
+				
+				// files of archives limited by their output size can contain consecutive parts of a file
+				if is_done {
+					return Ok(ControlFlow::Break(()));
+				}
+			}
+			
+			Ok(ControlFlow::Continue(()))
+		})?;
+		
+		if is_done {
+			break;
+		}
+	}
+	
+	// the size of data read from stdin is only stored in its last part
+	if total_size.is_some_and(|total_size| total_size.is_none_or(|total_size| written < total_size)) {
+		return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "parts of file are missing"));
+	}
+	
+	Ok(written)
+}
+
+/// Writes a part of a split file at its offset, creating the file if necessary
+fn unpack_part(entry: &mut tar::Entry<impl Read>, part: Part, directory: &Path) -> Result<PathBuf, io::Error> {
+	let relative_path = entry.path()?.into_owned();
+	
+	if !relative_path.components().all(|component| matches!(component, Component::Normal(_))) {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid path {}", relative_path.to_string_lossy())));
+	}
+	
+	let path = directory.join(relative_path);
+	
+	if let Some(parent) = path.parent() {
+		fs::create_dir_all(parent)?;
+	}
+	
+	// other parts might be written to the same file concurrently, so it must not be truncated
+	let mut file = File::options()
+		.write(true)
+		.create(true)
+		.truncate(false)
+		.open(&path)?;
+	
+	// the size of data read from stdin is only known in its last part, the other parts extend the file while writing
+	if let Some(total_size) = part.total_size {
+		file.set_len(total_size)?;
+	}
+	
+	file.seek(io::SeekFrom::Start(part.offset))?;
+	io::copy(entry, &mut file)?;
+	
+	if part.offset == 0 {
+		file.set_permissions(Permissions::from_mode(entry.header().mode()? & 0o7777))?;
+	}
+	
+	Ok(path)
 }
@@ -1,90 +1,257 @@
-use std::{io::{self, Read}, ops::ControlFlow};
+use std::{ffi::OsString, io::{self, Read, Seek, SeekFrom, Write}, ops::ControlFlow, os::unix::ffi::OsStringExt, path::PathBuf};
 
-use xz2::read::XzDecoder;
-
-use crate::{crypto::{DecryptReader, IV}, Key, BKY_HEADER};
+use crate::{compression::{Compression, Decoder}, crypto::{DecryptReader, IV}, segment::{Segment, SegmentReader}, Key, BKY_HEADER, BKY_HEADER_APPENDED, BKY_HEADER_V1, END_MARKER};
 
 pub struct SubArchive<R: Read> {
 	decrypter: DecryptReader<R>,
+	key: Key,
+	iv: IV,
+	flags: u32,
 	source_groups: Vec<SourceGroup>,
 	is_single_source: bool,
+	is_segmented: bool,
+	compression: Compression,
 }
 
+#[derive(Clone)]
 pub struct SourceGroup {
 	pub id: String,
 	pub size: u64,
 	pub flags: u32,
+	/// Only used if the sub archive is segmented, otherwise all groups share one compressed stream
+	pub segments: Vec<Segment>,
+	/// Set for the groups of archives sources were appended to, otherwise the data follows the previous group
+	pub location: Option<DataLocation>,
+	/// Files deleted since the archive this incremental archive is based on
+	pub deletions: Vec<PathBuf>,
+	/// Files which couldn't be packed completely or changed while they were read with the reason
+	pub warnings: Vec<(PathBuf, String)>,
 }
 
-impl<R: Read> SubArchive<R> {
+/// Where the segments of a source group start and the IV they are encrypted with
+#[derive(Clone, Copy)]
+pub struct DataLocation {
+	pub iv: IV,
+	/// Position in the archive, the keystream of every IV starts after the IV of the archive
+	pub start: u64,
+}
+
+impl DataLocation {
+	/// Size of the location in the header of a source group: iv(24) + start(8)
+	pub const SIZE: usize = size_of::<IV>() + size_of::<u64>();
+	
+	pub fn read(mut reader: impl Read) -> Result<Self, io::Error> {
+		let mut iv = IV::default();
+		reader.read_exact(&mut iv)?;
+		
+		let mut buf64 = [0u8; size_of::<u64>()];
+		reader.read_exact(&mut buf64)?;
+		let start = u64::from_le_bytes(buf64);
+		
+		Ok(Self {
+			iv,
+			start,
+		})
+	}
+	
+	pub fn write(&self, mut writer: impl Write) -> Result<(), io::Error> {
+		writer.write_all(&self.iv)?;
+		writer.write_all(&self.start.to_le_bytes())
+	}
+}
+
+impl SourceGroup {
+	/// Moves the decrypter to the data of the group if it is stored at its own location,
+	/// otherwise it is expected to be at the end of the previous group already
+	pub fn seek_to_data<R: Read + Seek>(&self, decrypter: &mut DecryptReader<R>, key: Key) -> Result<(), io::Error> {
+		if let Some(location) = self.location {
+			decrypter.set_iv(key, location.iv);
+			decrypter.seek(SeekFrom::Start(location.start))?;
+		}
+		
+		Ok(())
+	}
+}
+
+impl<R: Read + Seek> SubArchive<R> {
 	pub fn new(mut reader: R, key: Key) -> Result<Self, io::Error> {
 		let mut header = [0u8; BKY_HEADER.len()];
 		reader.read_exact(&mut header)?;
 		
-		if header != BKY_HEADER {
+		let is_v1 = header == BKY_HEADER_V1;
+		let is_appended = header == BKY_HEADER_APPENDED;
+		
+		if header != BKY_HEADER && !is_v1 && !is_appended {
 			panic!("Not a backy archive");
 		}
 		
 		let mut iv = IV::default();
 		reader.read_exact(&mut iv)?;
+		
+		// the header of archives sources were appended to is encrypted with the IV of the last append, which is stored last
+		if is_appended {
+			let data_start = reader.stream_position()?;
+			reader.seek(SeekFrom::End(-(size_of::<IV>() as i64)))?;
+			reader.read_exact(&mut iv)?;
+			reader.seek(SeekFrom::Start(data_start))?;
+		}
+		
 		let mut decrypter = DecryptReader::new(reader, key, iv);
 		
+		// followed by the length of the header, the end marker and the IV
+		if is_appended {
+			let end_len = (size_of::<IV>() + END_MARKER.len()) as i64;
+			
+			decrypter.seek(SeekFrom::End(-end_len))?;
+			let mut end = vec![0; END_MARKER.len()];
+			decrypter.read_exact(&mut end)?;
+			
+			if end != END_MARKER {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, "end of the appended data is damaged"));
+			}
+			
+			decrypter.seek(SeekFrom::End(-(size_of::<u64>() as i64 + end_len)))?;
+			let mut buf64 = [0u8; size_of::<u64>()];
+			decrypter.read_exact(&mut buf64)?;
+			let header_len = u64::from_le_bytes(buf64);
+			
+			decrypter.seek(SeekFrom::End(-(header_len as i64 + size_of::<u64>() as i64 + end_len)))?;
+		}
+		
 		let mut buf32 = [0u8; size_of::<u32>()];
-		let mut buf64 = [0u8; size_of::<u64>()];
 		
 		decrypter.read_exact(&mut buf32)?;
 		let flags = u32::from_le_bytes(buf32);
 		let is_single_source = flags & 1 != 0;
+		let is_segmented = flags & 2 != 0;
+		let is_padded = flags & 4 != 0;
+		// archives written as a stream store the header at the end
+		let has_trailer = flags & 8 != 0;
+		let has_end_marker = flags & 16 != 0;
 		
-		decrypter.read_exact(&mut buf32)?;
-		let groups_len = u32::from_le_bytes(buf32);
+		// the header of an archive whose writing was interrupted might not be written yet
+		if has_end_marker && !ends_with_end_marker(&mut decrypter)? {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "archive is incomplete, writing it was interrupted"));
+		}
 		
-		let mut source_groups = Vec::with_capacity(groups_len as usize);
+		let end_marker_len = if has_end_marker {
+			END_MARKER.len() as u64
+		} else {
+			0
+		};
 		
-		// header
-		for _ in 0..groups_len {
-			decrypter.read_exact(&mut buf32)?;
-			let id_len = u32::from_le_bytes(buf32);
-			let mut id_buf = vec![0; id_len as usize];
-			decrypter.read_exact(&mut id_buf[..])?;
-			let id = String::from_utf8(id_buf).unwrap(); // TODO: return custom error
+		// v1 archives don't store the compression and are always xz compressed
+		let compression = if is_v1 {
+			Compression::Xz
+		} else {
+			let mut buf8 = [0u8; size_of::<u8>()];
+			decrypter.read_exact(&mut buf8)?;
+			Compression::from_byte(buf8[0])
+				.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unknown compression {}", buf8[0])))?
+		};
+		
+		let source_groups = if has_trailer {
+			let data_start = decrypter.stream_position()?;
 			
+			decrypter.seek(SeekFrom::End(-((size_of::<u64>() as u64 + end_marker_len) as i64)))?;
+			let mut buf64 = [0u8; size_of::<u64>()];
 			decrypter.read_exact(&mut buf64)?;
-			let size = u64::from_le_bytes(buf64);
+			let trailer_len = u64::from_le_bytes(buf64);
 			
-			decrypter.read_exact(&mut buf32)?;
-			let flags = u32::from_le_bytes(buf32);
+			decrypter.seek(SeekFrom::End(-((size_of::<u64>() as u64 + trailer_len + end_marker_len) as i64)))?;
+			let source_groups = read_source_groups(&mut decrypter, is_segmented)?;
+			decrypter.seek(SeekFrom::Start(data_start))?;
 			
-			source_groups.push(SourceGroup {
-				id,
-				size,
-				flags,
-			});
+			source_groups
+		} else {
+			read_source_groups(&mut decrypter, is_segmented)?
+		};
+		
+		// the header of volumes limited by their output size is padded to the space reserved for it
+		if is_padded {
+			decrypter.read_exact(&mut buf32)?;
+			let padding_len = u32::from_le_bytes(buf32);
+			io::copy(&mut (&mut decrypter).take(padding_len as u64), &mut io::sink())?;
 		}
 		
 		Ok(Self {
 			decrypter,
+			key,
+			iv,
+			flags,
 			source_groups,
 			is_single_source,
+			is_segmented,
+			compression,
 		})
 	}
 	
+	/// Position in the inner reader at which the data of the source groups starts
+	pub fn data_start(&mut self) -> Result<u64, io::Error> {
+		self.decrypter.stream_position()
+	}
+}
+
+impl<R: Read> SubArchive<R> {
+	/// IV the header is encrypted with, which is the IV of the last append for archives sources were appended to
+	pub fn iv(&self) -> IV {
+		self.iv
+	}
+	
+	pub fn flags(&self) -> u32 {
+		self.flags
+	}
+	
 	pub fn is_single_source(&self) -> bool {
 		self.is_single_source
 	}
 	
+	pub fn is_segmented(&self) -> bool {
+		self.is_segmented
+	}
+	
+	pub fn into_source_groups(self) -> Vec<SourceGroup> {
+		self.source_groups
+	}
+	
+	pub fn compression(&self) -> Compression {
+		self.compression
+	}
+	
+	/// Splits the sub archive into the reader of the decrypted data of the source groups and their headers
+	pub fn into_parts(self) -> (DecryptReader<R>, Vec<SourceGroup>) {
+		(self.decrypter, self.source_groups)
+	}
+	
 	pub fn sources(&self) -> impl Iterator<Item = &str> {
 		self.source_groups.iter().map(|source_group| source_group.id.as_str())
 	}
 	
-	pub fn for_each_tar<F>(self, mut callback: F) -> Result<(), io::Error>
+	pub fn for_each_tar<F>(mut self, mut callback: F) -> Result<(), io::Error>
 	where
-		F: FnMut(&SourceGroup, &mut tar::Archive<io::Take<&mut XzDecoder<DecryptReader<R>>>>) -> Result<ControlFlow<()>, io::Error>,
+		R: Seek,
+		F: FnMut(&SourceGroup, &mut tar::Archive<&mut dyn Read>) -> Result<ControlFlow<()>, io::Error>,
 	{
-		let mut decoder = XzDecoder::new(self.decrypter);
+		if self.is_segmented {
+			for source_group in &self.source_groups {
+				source_group.seek_to_data(&mut self.decrypter, self.key)?;
+				let mut read = SegmentReader::new(&mut self.decrypter, &source_group.segments);
+				let mut archive = tar::Archive::new(&mut read as &mut dyn Read);
+				
+				if callback(source_group, &mut archive)?.is_break() {
+					break;
+				}
+				
+				read_to_end(archive.into_inner())?;
+			}
+			
+			return Ok(());
+		}
+		
+		let mut decoder = Decoder::new(self.decrypter, self.compression)?;
 		for source_group in self.source_groups {
-			let read = (&mut decoder).take(source_group.size);
-			let mut archive = tar::Archive::new(read);
+			let mut read = (&mut decoder).take(source_group.size);
+			let mut archive = tar::Archive::new(&mut read as &mut dyn Read);
 			
 			if callback(&source_group, &mut archive)?.is_break() {
 				break;
@@ -97,6 +264,114 @@ impl<R: Read> SubArchive<R> {
 	}
 }
 
+/// Whether the decrypted data ends with the end marker, keeping the position of the reader
+fn ends_with_end_marker<R: Read + Seek>(decrypter: &mut DecryptReader<R>) -> Result<bool, io::Error> {
+	let position = decrypter.stream_position()?;
+	let len = decrypter.seek(SeekFrom::End(0))?;
+	
+	if len < position + END_MARKER.len() as u64 {
+		decrypter.seek(SeekFrom::Start(position))?;
+		return Ok(false);
+	}
+	
+	decrypter.seek(SeekFrom::End(-(END_MARKER.len() as i64)))?;
+	let mut end = vec![0; END_MARKER.len()];
+	decrypter.read_exact(&mut end)?;
+	decrypter.seek(SeekFrom::Start(position))?;
+	
+	Ok(end == END_MARKER)
+}
+
+fn read_source_groups(mut decrypter: impl Read, is_segmented: bool) -> Result<Vec<SourceGroup>, io::Error> {
+	let mut buf32 = [0u8; size_of::<u32>()];
+	let mut buf64 = [0u8; size_of::<u64>()];
+	
+	decrypter.read_exact(&mut buf32)?;
+	let groups_len = u32::from_le_bytes(buf32);
+	
+	let mut source_groups = Vec::with_capacity(groups_len as usize);
+	
+	// header
+	for _ in 0..groups_len {
+		decrypter.read_exact(&mut buf32)?;
+		let id_len = u32::from_le_bytes(buf32);
+		let mut id_buf = vec![0; id_len as usize];
+		decrypter.read_exact(&mut id_buf[..])?;
+		let id = String::from_utf8(id_buf).unwrap(); // TODO: return custom error
+		
+		decrypter.read_exact(&mut buf64)?;
+		let size = u64::from_le_bytes(buf64);
+		
+		decrypter.read_exact(&mut buf32)?;
+		let flags = u32::from_le_bytes(buf32);
+		
+		let mut segments = Vec::new();
+		
+		if is_segmented {
+			decrypter.read_exact(&mut buf32)?;
+			let segments_len = u32::from_le_bytes(buf32);
+			
+			for _ in 0..segments_len {
+				segments.push(Segment::read_header(&mut decrypter)?);
+			}
+		}
+		
+		let location = if flags & 8 != 0 {
+			Some(DataLocation::read(&mut decrypter)?)
+		} else {
+			None
+		};
+		
+		let mut deletions = Vec::new();
+		
+		if flags & 2 != 0 {
+			decrypter.read_exact(&mut buf32)?;
+			let deletions_len = u32::from_le_bytes(buf32);
+			
+			for _ in 0..deletions_len {
+				decrypter.read_exact(&mut buf32)?;
+				let path_len = u32::from_le_bytes(buf32);
+				let mut path_buf = vec![0; path_len as usize];
+				decrypter.read_exact(&mut path_buf)?;
+				deletions.push(PathBuf::from(OsString::from_vec(path_buf)));
+			}
+		}
+		
+		let mut warnings = Vec::new();
+		
+		if flags & 4 != 0 {
+			decrypter.read_exact(&mut buf32)?;
+			let warnings_len = u32::from_le_bytes(buf32);
+			
+			for _ in 0..warnings_len {
+				decrypter.read_exact(&mut buf32)?;
+				let path_len = u32::from_le_bytes(buf32);
+				let mut path_buf = vec![0; path_len as usize];
+				decrypter.read_exact(&mut path_buf)?;
+				
+				decrypter.read_exact(&mut buf32)?;
+				let message_len = u32::from_le_bytes(buf32);
+				let mut message_buf = vec![0; message_len as usize];
+				decrypter.read_exact(&mut message_buf)?;
+				
+				warnings.push((PathBuf::from(OsString::from_vec(path_buf)), String::from_utf8_lossy(&message_buf).into_owned()));
+			}
+		}
+		
+		source_groups.push(SourceGroup {
+			id,
+			size,
+			flags,
+			segments,
+			location,
+			deletions,
+			warnings,
+		});
+	}
+	
+	Ok(source_groups)
+}
+
 fn read_to_end(mut read: impl Read) -> Result<(), io::Error> {
 	let mut buf = [0u8; 1024];
 	
@@ -108,3 +383,88 @@ fn read_to_end(mut read: impl Read) -> Result<(), io::Error> {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::{fs::{self, File}, io::Cursor, path::Path};
+	
+	use crate::{crypto::generate_key, pack::{pack, pack_stream, PackOptions}};
+	
+	use super::*;
+	
+	fn create_sources(dir: &Path) -> Vec<PathBuf> {
+		["first", "second"].iter()
+			.map(|source| {
+				let path = dir.join(source);
+				fs::create_dir(&path).unwrap();
+				fs::write(path.join("file"), source).unwrap();
+				path
+			})
+			.collect()
+	}
+	
+	fn read_files(sub_archive: SubArchive<impl Read + Seek>) -> Vec<(String, String)> {
+		let mut files = Vec::new();
+		
+		sub_archive.for_each_tar(|source_group, tar| {
+			for entry in tar.entries()? {
+				let mut entry = entry?;
+				
+				if entry.header().entry_type().is_file() {
+					let mut contents = String::new();
+					entry.read_to_string(&mut contents)?;
+					files.push((source_group.id.clone(), contents));
+				}
+			}
+			
+			Ok(ControlFlow::Continue(()))
+		}).unwrap();
+		
+		files
+	}
+	
+	#[test]
+	fn trailer_of_streamed_archives_is_read() {
+		let dir = tempfile::tempdir().unwrap();
+		let key = generate_key();
+		let mut data = Vec::new();
+		pack_stream(create_sources(dir.path()), &mut data, key, PackOptions::default()).unwrap();
+		
+		let sub_archive = SubArchive::new(Cursor::new(&data), key).unwrap();
+		assert_eq!(sub_archive.flags() & (8 | 16), 8 | 16);
+		assert_eq!(sub_archive.sources().collect::<Vec<_>>(), ["first", "second"]);
+		assert_eq!(read_files(sub_archive), [("first".into(), "first".into()), ("second".into(), "second".into())]);
+	}
+	
+	#[test]
+	fn header_of_archive_files_is_read() {
+		let dir = tempfile::tempdir().unwrap();
+		let key = generate_key();
+		let out = dir.path().join("out.bky");
+		pack(create_sources(dir.path()), out.clone(), key, PackOptions::default()).unwrap();
+		
+		let sub_archive = SubArchive::new(File::open(&out).unwrap(), key).unwrap();
+		assert_eq!(sub_archive.flags() & (8 | 16), 16);
+		assert_eq!(read_files(sub_archive), [("first".into(), "first".into()), ("second".into(), "second".into())]);
+	}
+	
+	#[test]
+	fn archives_without_end_marker_are_incomplete() {
+		let dir = tempfile::tempdir().unwrap();
+		let key = generate_key();
+		let sources = create_sources(dir.path());
+		
+		let mut data = Vec::new();
+		pack_stream(sources.clone(), &mut data, key, PackOptions::default()).unwrap();
+		data.pop();
+		let err = SubArchive::new(Cursor::new(&data), key).err().unwrap();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		
+		let out = dir.path().join("out.bky");
+		pack(sources, out.clone(), key, PackOptions::default()).unwrap();
+		let mut data = fs::read(&out).unwrap();
+		data.truncate(data.len() - END_MARKER.len());
+		let err = SubArchive::new(Cursor::new(&data), key).err().unwrap();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	}
+}
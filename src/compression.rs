@@ -0,0 +1,275 @@
+use std::{fs::File, io::{self, BufReader, Read, Write}, ops::RangeInclusive, path::Path};
+
+use xz2::{read::XzDecoder, stream::{Check, MtStreamBuilder}, write::XzEncoder};
+
+/// Extensions of file formats which are already compressed and are stored without compression by default
+pub const COMPRESSED_EXTENSIONS: &[&str] = &[
+	"7z", "aac", "apk", "avi", "avif", "br", "bky", "bz2", "deb", "docx", "epub", "flac", "gif", "gz", "heic", "jar", "jpeg",
+	"jpg", "lz", "lz4", "lzma", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odp", "ods", "odt", "ogg", "opus", "png", "pptx",
+	"rar", "rpm", "tbz2", "tgz", "txz", "webm", "webp", "woff", "woff2", "xlsx", "xz", "zip", "zst",
+];
+
+/// Number of bytes read from the start of a file to estimate whether it can be compressed
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Files with an estimated entropy above this (in bits per byte) are considered incompressible
+const INCOMPRESSIBLE_ENTROPY: f64 = 7.5;
+
+/// Compressed data is at most larger than its input by the input size divided by this
+const MAX_EXPANSION_DIVISOR: u64 = 256;
+
+/// Maximum size of the headers and footers of a compressed stream
+const MAX_STREAM_OVERHEAD: u64 = 4096;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+	None,
+	Xz,
+	Zstd,
+	Lz4,
+}
+
+impl Compression {
+	pub fn levels(self) -> RangeInclusive<u32> {
+		match self {
+			Compression::None => 0..=0,
+			Compression::Xz => 0..=9,
+			Compression::Zstd => 1..=22,
+			Compression::Lz4 => 0..=12,
+		}
+	}
+	
+	pub fn default_level(self) -> u32 {
+		match self {
+			Compression::None => 0,
+			Compression::Xz => 9,
+			Compression::Zstd => 3,
+			Compression::Lz4 => 0,
+		}
+	}
+	
+	/// Upper bound of the size of a compressed stream containing `size` bytes, including the overhead of finishing it
+	pub(crate) fn max_compressed_size(self, size: u64) -> u64 {
+		match self {
+			Compression::None => size,
+			// incompressible data is stored in blocks with small headers by all compressions
+			_ => size + size / MAX_EXPANSION_DIVISOR + MAX_STREAM_OVERHEAD,
+		}
+	}
+	
+	/// Largest number of bytes that can be compressed into at most `compressed_size` bytes
+	pub(crate) fn max_uncompressed_size(self, compressed_size: u64) -> u64 {
+		match self {
+			Compression::None => compressed_size,
+			_ => compressed_size.saturating_sub(MAX_STREAM_OVERHEAD) * MAX_EXPANSION_DIVISOR / (MAX_EXPANSION_DIVISOR + 1),
+		}
+	}
+	
+	pub(crate) fn to_byte(self) -> u8 {
+		match self {
+			Compression::None => 0,
+			Compression::Xz => 1,
+			Compression::Zstd => 2,
+			Compression::Lz4 => 3,
+		}
+	}
+	
+	pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+		match byte {
+			0 => Some(Compression::None),
+			1 => Some(Compression::Xz),
+			2 => Some(Compression::Zstd),
+			3 => Some(Compression::Lz4),
+			_ => None,
+		}
+	}
+}
+
+enum EncoderKind<W: Write> {
+	None(W),
+	Xz(XzEncoder<W>),
+	Zstd(zstd::Encoder<'static, W>),
+	Lz4(lz4::Encoder<W>),
+}
+
+pub struct Encoder<W: Write> {
+	kind: EncoderKind<W>,
+	total_in: u64,
+}
+
+impl<W: Write> Encoder<W> {
+	/// Creates a new encoder, using `threads` worker threads if the compression supports it,
+	/// or compressing on the calling thread if `threads` is 0
+	///
+	/// The multithreaded output doesn't depend on the number of threads, but differs from the single threaded output.
+	pub fn new(inner: W, compression: Compression, level: u32, threads: u32) -> Result<Self, io::Error> {
+		let kind = match compression {
+			Compression::None => EncoderKind::None(inner),
+			Compression::Xz if threads > 0 => {
+				let stream = MtStreamBuilder::new()
+					.threads(threads)
+					.preset(level)
+					.check(Check::Crc64)
+					.encoder()?;
+				EncoderKind::Xz(XzEncoder::new_stream(inner, stream))
+			},
+			Compression::Xz => EncoderKind::Xz(XzEncoder::new(inner, level)),
+			Compression::Zstd => {
+				let mut encoder = zstd::Encoder::new(inner, level as i32)?;
+				
+				if threads > 0 {
+					encoder.multithread(threads)?;
+				}
+				
+				EncoderKind::Zstd(encoder)
+			},
+			Compression::Lz4 => EncoderKind::Lz4(lz4::EncoderBuilder::new().level(level).build(inner)?),
+		};
+		
+		Ok(Self {
+			kind,
+			total_in: 0,
+		})
+	}
+	
+	pub fn compression(&self) -> Compression {
+		match self.kind {
+			EncoderKind::None(_) => Compression::None,
+			EncoderKind::Xz(_) => Compression::Xz,
+			EncoderKind::Zstd(_) => Compression::Zstd,
+			EncoderKind::Lz4(_) => Compression::Lz4,
+		}
+	}
+	
+	pub fn get_ref(&self) -> &W {
+		match &self.kind {
+			EncoderKind::None(inner) => inner,
+			EncoderKind::Xz(encoder) => encoder.get_ref(),
+			EncoderKind::Zstd(encoder) => encoder.get_ref(),
+			EncoderKind::Lz4(encoder) => encoder.writer(),
+		}
+	}
+	
+	/// Number of uncompressed bytes written so far
+	pub fn total_in(&self) -> u64 {
+		self.total_in
+	}
+	
+	pub fn finish(self) -> Result<W, io::Error> {
+		match self.kind {
+			EncoderKind::None(inner) => Ok(inner),
+			EncoderKind::Xz(encoder) => encoder.finish(),
+			EncoderKind::Zstd(encoder) => encoder.finish(),
+			EncoderKind::Lz4(encoder) => {
+				let (inner, result) = encoder.finish();
+				result?;
+				Ok(inner)
+			},
+		}
+	}
+}
+
+impl<W: Write> Write for Encoder<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let written = match &mut self.kind {
+			EncoderKind::None(inner) => inner.write(buf)?,
+			EncoderKind::Xz(encoder) => encoder.write(buf)?,
+			EncoderKind::Zstd(encoder) => encoder.write(buf)?,
+			EncoderKind::Lz4(encoder) => encoder.write(buf)?,
+		};
+		
+		self.total_in += written as u64;
+		Ok(written)
+	}
+	
+	fn flush(&mut self) -> io::Result<()> {
+		match &mut self.kind {
+			EncoderKind::None(inner) => inner.flush(),
+			EncoderKind::Xz(encoder) => encoder.flush(),
+			EncoderKind::Zstd(encoder) => encoder.flush(),
+			EncoderKind::Lz4(encoder) => encoder.flush(),
+		}
+	}
+}
+
+pub enum Decoder<R: Read> {
+	None(R),
+	Xz(XzDecoder<R>),
+	Zstd(zstd::Decoder<'static, BufReader<R>>),
+	Lz4(lz4::Decoder<R>),
+}
+
+impl<R: Read> Decoder<R> {
+	pub fn new(inner: R, compression: Compression) -> Result<Self, io::Error> {
+		let decoder = match compression {
+			Compression::None => Decoder::None(inner),
+			Compression::Xz => Decoder::Xz(XzDecoder::new(inner)),
+			Compression::Zstd => Decoder::Zstd(zstd::Decoder::new(inner)?),
+			Compression::Lz4 => Decoder::Lz4(lz4::Decoder::new(inner)?),
+		};
+		
+		Ok(decoder)
+	}
+	
+	pub fn into_inner(self) -> Result<R, io::Error> {
+		match self {
+			Decoder::None(inner) => Ok(inner),
+			Decoder::Xz(decoder) => Ok(decoder.into_inner()),
+			Decoder::Zstd(decoder) => Ok(decoder.finish().into_inner()),
+			Decoder::Lz4(decoder) => {
+				let (inner, result) = decoder.finish();
+				result?;
+				Ok(inner)
+			},
+		}
+	}
+}
+
+impl<R: Read> Read for Decoder<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match self {
+			Decoder::None(inner) => inner.read(buf),
+			Decoder::Xz(decoder) => decoder.read(buf),
+			Decoder::Zstd(decoder) => decoder.read(buf),
+			Decoder::Lz4(decoder) => decoder.read(buf),
+		}
+	}
+}
+
+pub fn has_compressed_extension(path: &Path, extensions: &[String]) -> bool {
+	let Some(extension) = path.extension() else {
+		return false;
+	};
+	
+	extensions.iter()
+		.any(|compressed| extension.eq_ignore_ascii_case(compressed))
+}
+
+/// Estimates whether a file is already compressed by calculating the entropy of its first bytes
+pub fn looks_incompressible(path: &Path) -> Result<bool, io::Error> {
+	let mut sample = Vec::with_capacity(SAMPLE_SIZE);
+	File::open(path)?
+		.take(SAMPLE_SIZE as u64)
+		.read_to_end(&mut sample)?;
+	
+	if sample.is_empty() {
+		return Ok(false);
+	}
+	
+	let mut counts = [0u64; 256];
+	
+	for byte in &sample {
+		counts[*byte as usize] += 1;
+	}
+	
+	let len = sample.len() as f64;
+	let entropy: f64 = counts.iter()
+		.filter(|&&count| count > 0)
+		.map(|&count| {
+			let probability = count as f64 / len;
+			-probability * probability.log2()
+		})
+		.sum();
+	
+	Ok(entropy > INCOMPRESSIBLE_ENTROPY)
+}
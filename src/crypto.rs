@@ -1,6 +1,6 @@
-use std::io::{Read, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
-use chacha20::{cipher::{consts::{U24, U32}, generic_array::GenericArray, KeyIvInit, StreamCipher}, XChaCha20};
+use chacha20::{cipher::{consts::{U24, U32}, generic_array::GenericArray, KeyIvInit, StreamCipher, StreamCipherSeek}, XChaCha20};
 
 pub type Key = GenericArray<u8, U32>;
 pub type IV = GenericArray<u8, U24>;
@@ -17,6 +17,61 @@ pub fn generate_iv() -> IV {
 	iv
 }
 
+/// Creates a hasher for deriving an IV from the data to be encrypted, so the same data always results in the same output
+fn iv_hasher(key: &Key) -> blake3::Hasher {
+	let iv_key = blake3::derive_key("backy archive iv", key);
+	blake3::Hasher::new_keyed(&iv_key)
+}
+
+fn finalize_iv(hasher: &blake3::Hasher) -> IV {
+	let mut iv = IV::default();
+	hasher.finalize_xof().fill(&mut iv);
+	iv
+}
+
+/// Size of the chunks data is encrypted again in by `replace_iv`
+const REENCRYPT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Derives an IV from the data following the IV at `iv_position`, which is encrypted with that IV,
+/// then encrypts the data again with the derived IV and writes it in place of the previous one
+///
+/// The IV only depends on the data which was actually written this way, so different data never shares a keystream.
+pub fn replace_iv<F: Read + Write + Seek>(mut file: F, iv_position: u64, key: Key, iv: IV) -> io::Result<IV> {
+	let data_position = iv_position + size_of::<IV>() as u64;
+	
+	file.seek(SeekFrom::Start(data_position))?;
+	let mut hasher = iv_hasher(&key);
+	io::copy(&mut DecryptReader::new(&mut file, key, iv), &mut hasher)?;
+	let derived_iv = finalize_iv(&hasher);
+	
+	file.seek(SeekFrom::Start(data_position))?;
+	let mut cipher = XChaCha20::new(&key, &iv);
+	let mut derived_cipher = XChaCha20::new(&key, &derived_iv);
+	let mut buffer = vec![0; REENCRYPT_BUFFER_SIZE];
+	
+	loop {
+		let len = match file.read(&mut buffer) {
+			Ok(0) => break,
+			Ok(len) => len,
+			Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+			Err(err) => return Err(err),
+		};
+		
+		let buffer = &mut buffer[..len];
+		cipher.apply_keystream(buffer);
+		derived_cipher.apply_keystream(buffer);
+		
+		file.seek(SeekFrom::Current(-(len as i64)))?;
+		file.write_all(buffer)?;
+	}
+	
+	file.seek(SeekFrom::Start(iv_position))?;
+	file.write_all(&derived_iv)?;
+	file.flush()?;
+	
+	Ok(derived_iv)
+}
+
 pub struct EncryptWriter<W: Write> {
 	inner: W,
 	cipher: XChaCha20,
@@ -33,6 +88,10 @@ impl<W: Write> EncryptWriter<W> {
 			buffer: Vec::new(),
 		}
 	}
+	
+	pub fn into_inner(self) -> W {
+		self.inner
+	}
 }
 
 impl<W: Write> Write for EncryptWriter<W> {
@@ -56,6 +115,21 @@ impl<W: Write> Write for EncryptWriter<W> {
 	}
 }
 
+/// Seeking moves the keystream along with the inner writer, so data can be overwritten
+impl<W: Write + Seek> Seek for EncryptWriter<W> {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let old_position = self.inner.stream_position()?;
+		let new_position = self.inner.seek(pos)?;
+		let keystream_position = (self.cipher.current_pos::<u64>() + new_position).checked_sub(old_position)
+			.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "cannot seek before the start of the encrypted data"))?;
+		
+		self.cipher.try_seek(keystream_position)
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "cannot seek past the end of the keystream"))?;
+		
+		Ok(new_position)
+	}
+}
+
 pub struct DecryptReader<R: Read> {
 	inner: R,
 	cipher: XChaCha20,
@@ -70,6 +144,13 @@ impl<R: Read> DecryptReader<R> {
 			cipher,
 		}
 	}
+	
+	/// Continues decrypting with another IV, whose keystream starts at the same position of the inner reader
+	pub fn set_iv(&mut self, key: Key, iv: IV) {
+		let keystream_position = self.cipher.current_pos::<u64>();
+		self.cipher = XChaCha20::new(&key, &iv);
+		self.cipher.seek(keystream_position);
+	}
 }
 
 impl<R: Read> Read for DecryptReader<R> {
@@ -85,3 +166,17 @@ impl<R: Read> Read for DecryptReader<R> {
 		Ok(())
 	}
 }
+
+impl<R: Read + Seek> Seek for DecryptReader<R> {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let old_position = self.inner.stream_position()?;
+		let new_position = self.inner.seek(pos)?;
+		let keystream_position = (self.cipher.current_pos::<u64>() + new_position).checked_sub(old_position)
+			.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "cannot seek before the start of the encrypted data"))?;
+		
+		self.cipher.try_seek(keystream_position)
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "cannot seek past the end of the keystream"))?;
+		
+		Ok(new_position)
+	}
+}
@@ -0,0 +1,73 @@
+use std::{io::{self, Write}, ops::ControlFlow, path::{Path, PathBuf}, thread};
+
+use crate::{archive::{copy_file, Archive}, crypto::Key, part::Part};
+
+/// Writes the files of an archive as a single tar stream to `writer`, with each source as a top level directory or file
+///
+/// Files split into parts are joined again, while the deletions of incremental archives can't be represented and are left out.
+pub fn export(archive: PathBuf, writer: impl Write, key: Key) -> Result<(), io::Error> {
+	let archive = Archive::new(archive, key);
+	let mut tar_builder = tar::Builder::new(writer);
+	
+	for (i, sub_archive) in archive.sub_archives()?.enumerate() {
+		sub_archive?.for_each_tar(|source_group, tar| {
+			let is_file = source_group.flags & 1 != 0;
+			
+			for entry in tar.entries()? {
+				let mut entry = entry?;
+				let part = Part::read(&mut entry)?;
+				let path = entry.path()?.into_owned();
+				let mut header = entry.header().clone();
+				
+				let export_path = if is_file {
+					path.clone()
+				} else {
+					Path::new(&source_group.id).join(&path)
+				};
+				
+				let Some(part) = part else {
+					match entry.link_name()? {
+						Some(link_name) if header.entry_type().is_hard_link() && !is_file => {
+							tar_builder.append_link(&mut header, &export_path, Path::new(&source_group.id).join(link_name))?;
+						},
+						Some(link_name) => tar_builder.append_link(&mut header, &export_path, link_name)?,
+						None => tar_builder.append_data(&mut header, &export_path, entry)?,
+					}
+					
+					continue;
+				};
+				
+				// the file was already written together with its first part
+				if part.offset > 0 {
+					continue;
+				}
+				
+				// the following parts can be stored in later files, which are read at the same time while the file is written
+				let copy_parts = |writer: Box<dyn Write + Send>| copy_file(archive.sub_archives()?.skip(i), Some(&source_group.id), &path, writer);
+				
+				let size = match part.total_size {
+					Some(total_size) => total_size,
+					// the size of data read from stdin is only stored in its last part
+					None => copy_parts(Box::new(io::sink()))?,
+				};
+				header.set_size(size);
+				
+				let (reader, writer) = io::pipe()?;
+				
+				thread::scope(|scope| {
+					let copy = scope.spawn(|| copy_parts(Box::new(writer)));
+					tar_builder.append_data(&mut header, &export_path, reader)?;
+					
+					match copy.join().expect("copying the parts of a file should not panic")? {
+						written if written == size => Ok(()),
+						_ => Err(io::Error::new(io::ErrorKind::InvalidData, "size of file doesn't match the size of its parts")),
+					}
+				})?;
+			}
+			
+			Ok(ControlFlow::Continue(()))
+		})?;
+	}
+	
+	tar_builder.into_inner()?.flush()
+}
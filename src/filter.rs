@@ -0,0 +1,560 @@
+use std::{borrow::Cow, collections::HashMap, fs::{self, File}, io::{self, Read}, mem, os::unix::ffi::OsStrExt, path::{Path, PathBuf}};
+
+/// Name of the files containing patterns of files to exclude from the directory they are located in
+const IGNORE_FILE_NAME: &str = ".backyignore";
+
+const GITIGNORE_FILE_NAME: &str = ".gitignore";
+
+/// Directory marking the root of a git repository
+const GIT_DIR_NAME: &str = ".git";
+
+/// File in the root of a git repository containing exclude patterns which aren't part of the repository
+const GIT_EXCLUDE_PATH: &str = ".git/info/exclude";
+
+/// Name of the file marking a directory as a cache, see https://bford.info/cachedir/
+const CACHEDIR_TAG_NAME: &str = "CACHEDIR.TAG";
+
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Directories skipped when backing up `/`, which contain virtual file systems or temporary files
+const SYSTEM_EXCLUDES: &[&str] = &["/proc/", "/sys/", "/dev/", "/run/", "/tmp/"];
+
+/// Lists the mounted file systems of the current process on Linux
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+/// Named sets of exclude patterns
+#[derive(Clone, Copy, Debug)]
+pub enum Preset {
+	/// Build outputs, dependencies and caches of common development tools
+	Dev,
+}
+
+impl Preset {
+	fn patterns(self) -> &'static [&'static str] {
+		match self {
+			Preset::Dev => &["target/", "node_modules/", ".venv/", "__pycache__/"],
+		}
+	}
+}
+
+/// Decides which files below a source are packed, based on gitignore style patterns
+///
+/// The last pattern matching a path decides whether it is excluded or included, so include patterns override
+/// the exclude patterns before them. The include patterns of the options override all exclude patterns,
+/// including those of ignore files. Files below an excluded directory can't be included again.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+	patterns: Vec<Pattern>,
+	/// Patterns of files which are packed even if an exclude pattern or ignore file matches them
+	include_patterns: Vec<Pattern>,
+	/// Names of the files in each directory whose patterns apply to the directory, later ones take precedence
+	ignore_file_names: Vec<&'static str>,
+	/// Exclude directories containing a `CACHEDIR.TAG` file
+	exclude_caches: bool,
+	one_file_system: bool,
+	exclude_system_dirs: bool,
+	/// Types of the mounted file systems by their device ID, only containing those which are excluded
+	excluded_fs_types: HashMap<u64, String>,
+}
+
+impl Filter {
+	/// Creates a filter applying the patterns of the presets, the exclude patterns and the patterns read from `exclude_from`
+	/// in this order, followed by the ignore files and the include patterns
+	pub fn new(presets: &[Preset], exclude: &[String], include: &[String], exclude_from: &[PathBuf]) -> Result<Self, io::Error> {
+		let mut filter = Self::default();
+		
+		for preset in presets {
+			filter.patterns.extend(preset.patterns().iter().filter_map(|pattern| Pattern::new(pattern, false)));
+		}
+		
+		filter.patterns.extend(exclude.iter().filter_map(|pattern| Pattern::new(pattern, false)));
+		
+		for path in exclude_from {
+			filter.patterns.extend(Self::read(path)?.patterns);
+		}
+		
+		filter.include_patterns.extend(include.iter().filter_map(|pattern| Pattern::new(pattern, true)));
+		filter.ignore_file_names.push(IGNORE_FILE_NAME);
+		
+		Ok(filter)
+	}
+	
+	/// Also applies the patterns of `.gitignore` files before those of `.backyignore` files in the same directory,
+	/// including the ones above a source up to the root of its git repository and its `.git/info/exclude`
+	pub fn respect_gitignore(mut self, respect_gitignore: bool) -> Self {
+		if respect_gitignore {
+			self.ignore_file_names.insert(0, GITIGNORE_FILE_NAME);
+		}
+		
+		self
+	}
+	
+	/// Excludes directories containing a `CACHEDIR.TAG` file
+	pub fn exclude_caches(mut self, exclude_caches: bool) -> Self {
+		self.exclude_caches = exclude_caches;
+		self
+	}
+	
+	/// Skips directories on other file systems than their source, or only those with one of the given types
+	pub fn exclude_file_systems(mut self, one_file_system: bool, fs_types: &[String]) -> Result<Self, io::Error> {
+		self.one_file_system = one_file_system;
+		
+		if !fs_types.is_empty() {
+			self.excluded_fs_types = read_fs_types()?;
+			self.excluded_fs_types.retain(|_, fs_type| fs_types.contains(fs_type));
+		}
+		
+		Ok(self)
+	}
+	
+	/// Skips `/proc`, `/sys`, `/dev`, `/run` and `/tmp` when backing up `/`
+	pub fn exclude_system_dirs(mut self, exclude_system_dirs: bool) -> Self {
+		self.exclude_system_dirs = exclude_system_dirs;
+		self
+	}
+	
+	/// Returns the filter to apply below a source, which also skips the system directories if the source is `/`
+	///
+	/// The system directories have the lowest precedence, so they can be included again by include patterns.
+	pub fn for_source(&self, source_path: &Path) -> Cow<'_, Self> {
+		if !self.exclude_system_dirs || source_path.parent().is_some() {
+			return Cow::Borrowed(self);
+		}
+		
+		let mut filter = self.clone();
+		filter.patterns.splice(0..0, SYSTEM_EXCLUDES.iter().filter_map(|pattern| Pattern::new(pattern, false)));
+		
+		Cow::Owned(filter)
+	}
+	
+	/// Reads the patterns of an ignore file, one per line, with `!` marking include patterns and `#` comments
+	pub fn read(path: &Path) -> Result<Self, io::Error> {
+		let patterns = fs::read_to_string(path)?
+			.lines()
+			.filter(|line| !line.starts_with('#'))
+			.filter_map(|line| match line.strip_prefix('!') {
+				Some(pattern) => Pattern::new(pattern, true),
+				None => Pattern::new(line.strip_prefix('\\').unwrap_or(line), false),
+			})
+			.collect();
+		
+		Ok(Self {
+			patterns,
+			..Self::default()
+		})
+	}
+	
+	/// Reads the patterns of the ignore files in a directory, returns `None` if it doesn't contain any
+	pub fn read_ignore_files(&self, directory: &Path) -> Result<Option<Self>, io::Error> {
+		let mut patterns = Vec::new();
+		
+		for name in &self.ignore_file_names {
+			let path = directory.join(name);
+			
+			if path.is_file() {
+				patterns.append(&mut Self::read(&path)?.patterns);
+			}
+		}
+		
+		if patterns.is_empty() {
+			return Ok(None);
+		}
+		
+		Ok(Some(Self {
+			patterns,
+			..Self::default()
+		}))
+	}
+	
+	/// Reads the patterns applying to a source from above it if `.gitignore` files are respected, which are those of
+	/// `.git/info/exclude` and of the `.gitignore` files from the root of its git repository down to its parent directory,
+	/// returns them in the order they are applied with the directories their patterns are relative to
+	pub fn read_parent_ignore_files(&self, source_path: &Path) -> Result<Vec<(PathBuf, Self)>, io::Error> {
+		if !self.ignore_file_names.contains(&GITIGNORE_FILE_NAME) {
+			return Ok(Vec::new());
+		}
+		
+		let Some(repository) = source_path.ancestors().find(|directory| directory.join(GIT_DIR_NAME).exists()) else {
+			return Ok(Vec::new());
+		};
+		
+		let mut ignore_files = Vec::new();
+		let exclude_path = repository.join(GIT_EXCLUDE_PATH);
+		
+		if exclude_path.is_file() {
+			ignore_files.push((repository.to_owned(), Self::read(&exclude_path)?));
+		}
+		
+		// the ignore files of the source itself are read while walking it
+		let mut directories: Vec<&Path> = source_path.ancestors()
+			.skip(1)
+			.take_while(|directory| directory.starts_with(repository))
+			.collect();
+		directories.reverse();
+		
+		for directory in directories {
+			let path = directory.join(GITIGNORE_FILE_NAME);
+			
+			if path.is_file() {
+				ignore_files.push((directory.to_owned(), Self::read(&path)?));
+			}
+		}
+		
+		Ok(ignore_files)
+	}
+	
+	/// Whether the directory is excluded as a cache, because it contains a `CACHEDIR.TAG` file starting with its signature
+	pub fn is_excluded_cache(&self, directory: &Path) -> Result<bool, io::Error> {
+		if !self.exclude_caches {
+			return Ok(false);
+		}
+		
+		let mut signature = Vec::new();
+		
+		match File::open(directory.join(CACHEDIR_TAG_NAME)) {
+			Ok(file) => file.take(CACHEDIR_TAG_SIGNATURE.len() as u64).read_to_end(&mut signature)?,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+			Err(err) => return Err(err),
+		};
+		
+		Ok(signature == CACHEDIR_TAG_SIGNATURE)
+	}
+	
+	/// Whether directories on other file systems than their source are excluded, so their devices have to be checked
+	pub fn checks_devices(&self) -> bool {
+		self.one_file_system || !self.excluded_fs_types.is_empty()
+	}
+	
+	/// Whether a directory on the device `device` is excluded because its file system differs from the one of its source,
+	/// returns the type of the file system if it is known
+	pub fn is_excluded_device(&self, device: u64, source_device: u64) -> Option<Option<&str>> {
+		if device == source_device {
+			return None;
+		}
+		
+		match self.excluded_fs_types.get(&device) {
+			Some(fs_type) => Some(Some(fs_type)),
+			None if self.one_file_system => Some(None),
+			None => None,
+		}
+	}
+	
+	/// Whether the last pattern matching the path relative to the directory of the filter excludes it, `None` if none matches
+	pub fn is_excluded(&self, relative_path: &Path, is_dir: bool) -> Option<bool> {
+		self.patterns.iter()
+			.rev()
+			.find(|pattern| pattern.matches(relative_path, is_dir))
+			.map(|pattern| !pattern.is_include)
+	}
+	
+	/// Whether an include pattern of the options matches the path relative to the source, which overrides all other patterns
+	pub fn is_included(&self, relative_path: &Path, is_dir: bool) -> bool {
+		self.include_patterns.iter().any(|pattern| pattern.matches(relative_path, is_dir))
+	}
+}
+
+/// Reads the types of the mounted file systems by their device ID
+fn read_fs_types() -> Result<HashMap<u64, String>, io::Error> {
+	let mountinfo = fs::read_to_string(MOUNTINFO_PATH).map_err(|err| io::Error::new(
+		err.kind(),
+		format!("failed to read the mounted file systems from {MOUNTINFO_PATH}: {err}")
+	))?;
+	let mut fs_types = HashMap::new();
+	
+	// the third field is the device ID as major:minor, the type follows the separator after the optional fields
+	for line in mountinfo.lines() {
+		let mut fields = line.split(' ');
+		
+		let Some((major, minor)) = fields.nth(2).and_then(|device| device.split_once(':')) else {
+			continue;
+		};
+		let Some(fs_type) = fields.skip_while(|field| *field != "-").nth(1) else {
+			continue;
+		};
+		let (Ok(major), Ok(minor)) = (major.parse::<u64>(), minor.parse::<u64>()) else {
+			continue;
+		};
+		
+		// the encoding of device IDs used by glibc and musl
+		let device = (major & 0xfffff000) << 32 | (major & 0xfff) << 8 | (minor & 0xffffff00) << 12 | minor & 0xff;
+		fs_types.insert(device, fs_type.to_owned());
+	}
+	
+	Ok(fs_types)
+}
+
+#[derive(Clone, Debug)]
+struct Pattern {
+	glob: Vec<Token>,
+	/// The pattern doesn't contain a slash, so it is matched against the name of files at any depth
+	is_name: bool,
+	/// The pattern ends with a slash and only matches directories
+	is_dir_only: bool,
+	is_include: bool,
+}
+
+impl Pattern {
+	/// Parses a pattern, returns `None` if it is empty
+	fn new(pattern: &str, is_include: bool) -> Option<Self> {
+		let pattern = pattern.trim_end();
+		let (pattern, is_dir_only) = match pattern.strip_suffix('/') {
+			Some(pattern) => (pattern, true),
+			None => (pattern, false),
+		};
+		
+		if pattern.is_empty() {
+			return None;
+		}
+		
+		// patterns containing a slash are relative to the directory they apply to
+		let is_name = !pattern.contains('/');
+		let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+		
+		Some(Self {
+			glob: parse_glob(pattern.as_bytes()),
+			is_name,
+			is_dir_only,
+			is_include,
+		})
+	}
+	
+	fn matches(&self, relative_path: &Path, is_dir: bool) -> bool {
+		if self.is_dir_only && !is_dir {
+			return false;
+		}
+		
+		let path = if self.is_name {
+			relative_path.file_name().unwrap_or_default()
+		} else {
+			relative_path.as_os_str()
+		};
+		
+		glob_matches(&self.glob, path.as_bytes())
+	}
+}
+
+/// Part of a glob, which matches a single byte unless it is a wildcard
+#[derive(Clone, Debug)]
+enum Token {
+	Byte(u8),
+	/// `?`, which matches any byte but a slash
+	AnyByte,
+	/// `[...]` with the ranges of bytes it contains and whether it is negated, which never matches a slash
+	Class(Vec<(u8, u8)>, bool),
+	/// `*`, which matches any number of bytes but slashes
+	Star,
+	/// `**`, which matches any number of bytes including slashes
+	DoubleStar,
+	/// `**/`, which matches any number of directories, including none
+	Directories,
+}
+
+/// Parses a glob with `*`, `?`, `[...]`, `**` and `\` escaping the next byte
+fn parse_glob(mut glob: &[u8]) -> Vec<Token> {
+	let mut tokens = Vec::new();
+	
+	while let [byte, rest @ ..] = glob {
+		glob = rest;
+		
+		let token = match byte {
+			b'*' => match glob {
+				[b'*', b'/', rest @ ..] => {
+					glob = rest;
+					Token::Directories
+				},
+				[b'*', rest @ ..] => {
+					glob = rest;
+					Token::DoubleStar
+				},
+				_ => Token::Star,
+			},
+			b'?' => Token::AnyByte,
+			b'[' => match parse_class(glob) {
+				Some((class, rest)) => {
+					glob = rest;
+					class
+				},
+				// without a closing bracket it is matched literally
+				None => Token::Byte(b'['),
+			},
+			b'\\' => match glob {
+				[byte, rest @ ..] => {
+					glob = rest;
+					Token::Byte(*byte)
+				},
+				[] => Token::Byte(b'\\'),
+			},
+			byte => Token::Byte(*byte),
+		};
+		
+		tokens.push(token);
+	}
+	
+	tokens
+}
+
+/// Parses a character class after its opening bracket, returns it and the rest of the glob
+fn parse_class(class: &[u8]) -> Option<(Token, &[u8])> {
+	let (is_negated, class) = match class {
+		[b'!' | b'^', class @ ..] => (true, class),
+		class => (false, class),
+	};
+	
+	// a closing bracket at the start is part of the class
+	let end = class.iter()
+		.skip(1)
+		.position(|&byte| byte == b']')? + 1;
+	let (mut items, rest) = (&class[..end], &class[end + 1..]);
+	let mut ranges = Vec::new();
+	
+	while let [start, tail @ ..] = items {
+		match tail {
+			[b'-', end, tail @ ..] => {
+				ranges.push((*start, *end));
+				items = tail;
+			},
+			_ => {
+				ranges.push((*start, *start));
+				items = tail;
+			},
+		}
+	}
+	
+	Some((Token::Class(ranges, is_negated), rest))
+}
+
+/// Matches the text against a parsed glob, in time proportional to the length of the text times the length of the glob
+///
+/// Instead of backtracking, all positions in the glob the text read so far can end at are tracked at once.
+fn glob_matches(glob: &[Token], text: &[u8]) -> bool {
+	let mut positions = vec![false; glob.len() + 1];
+	let mut next = positions.clone();
+	positions[0] = true;
+	skip_empty_matches(glob, &mut positions);
+	
+	for &byte in text {
+		next.fill(false);
+		
+		for (i, token) in glob.iter().enumerate().filter(|&(i, _)| positions[i]) {
+			match token {
+				Token::Byte(expected) => next[i + 1] |= byte == *expected,
+				Token::AnyByte => next[i + 1] |= byte != b'/',
+				Token::Class(ranges, is_negated) => {
+					let is_match = ranges.iter().any(|&(start, end)| (start..=end).contains(&byte)) != *is_negated;
+					next[i + 1] |= byte != b'/' && is_match;
+				},
+				Token::Star => next[i] |= byte != b'/',
+				Token::DoubleStar => next[i] = true,
+				Token::Directories => {
+					next[i] = true;
+					next[i + 1] |= byte == b'/';
+				},
+			}
+		}
+		
+		skip_empty_matches(glob, &mut next);
+		
+		if !next.contains(&true) {
+			return false;
+		}
+		
+		mem::swap(&mut positions, &mut next);
+	}
+	
+	positions[glob.len()]
+}
+
+/// Adds the positions after the wildcards which can match nothing to the positions reached in the glob
+fn skip_empty_matches(glob: &[Token], positions: &mut [bool]) {
+	for (i, token) in glob.iter().enumerate() {
+		if positions[i] && matches!(token, Token::Star | Token::DoubleStar | Token::Directories) {
+			positions[i + 1] = true;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	fn matches(pattern: &str, path: &str, is_dir: bool) -> bool {
+		Pattern::new(pattern, false).unwrap().matches(Path::new(path), is_dir)
+	}
+	
+	#[test]
+	fn star_matches_within_a_name() {
+		assert!(matches("*.log", "a/b/x.log", false));
+		assert!(matches("src/*.rs", "src/main.rs", false));
+		assert!(!matches("src/*.rs", "src/a/main.rs", false));
+		assert!(matches("a*b*c", "abc", false));
+	}
+	
+	#[test]
+	fn question_mark_matches_one_byte_but_a_slash() {
+		assert!(matches("?.txt", "a.txt", false));
+		assert!(!matches("?.txt", "ab.txt", false));
+		assert!(!matches("x/a?b", "x/a/b", false));
+	}
+	
+	#[test]
+	fn character_classes() {
+		assert!(matches("[a-c]x", "bx", false));
+		assert!(!matches("[a-c]x", "dx", false));
+		assert!(matches("[!a-c]x", "dx", false));
+		assert!(!matches("[^a-c]x", "ax", false));
+		assert!(matches("[]]", "]", false));
+		assert!(matches("[abc", "[abc", false));
+		assert!(!matches("x[/]y", "x/y", false));
+	}
+	
+	#[test]
+	fn backslash_escapes_wildcards() {
+		assert!(matches("\\*", "*", false));
+		assert!(!matches("\\*", "a", false));
+	}
+	
+	#[test]
+	fn double_star_matches_directories() {
+		assert!(matches("**/foo", "foo", false));
+		assert!(matches("**/foo", "a/b/foo", false));
+		assert!(matches("a/**/b", "a/b", false));
+		assert!(matches("a/**/b", "a/x/y/b", false));
+		assert!(matches("a/**", "a/x/y", false));
+		assert!(!matches("a/**", "b/x", false));
+	}
+	
+	#[test]
+	fn patterns_with_a_slash_are_anchored() {
+		assert!(matches("/foo", "foo", false));
+		assert!(!matches("/foo", "a/foo", false));
+		assert!(matches("foo", "a/foo", false));
+		assert!(matches("a/foo", "a/foo", false));
+		assert!(!matches("a/foo", "x/a/foo", false));
+	}
+	
+	#[test]
+	fn trailing_slash_only_matches_directories() {
+		assert!(matches("build/", "build", true));
+		assert!(!matches("build/", "build", false));
+	}
+	
+	#[test]
+	fn last_matching_pattern_of_ignore_file_decides() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join(IGNORE_FILE_NAME);
+		fs::write(&path, "# comment\n*.log\n!keep.log\n\\!literal\n").unwrap();
+		let filter = Filter::read(&path).unwrap();
+		
+		assert_eq!(filter.is_excluded(Path::new("x.log"), false), Some(true));
+		assert_eq!(filter.is_excluded(Path::new("keep.log"), false), Some(false));
+		assert_eq!(filter.is_excluded(Path::new("!literal"), false), Some(true));
+		assert_eq!(filter.is_excluded(Path::new("# comment"), false), None);
+	}
+	
+	#[test]
+	fn many_stars_do_not_backtrack() {
+		let text = "a".repeat(10_000);
+		assert!(!matches("*a*a*a*a*a*a*a*a*a*a*b", &text, false));
+		assert!(matches("**a**a**a**a**a", &text, false));
+	}
+}
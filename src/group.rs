@@ -1,22 +1,55 @@
-use crate::Entry;
+use std::cmp::Reverse;
+
+use crate::{part::split_entry, warning::Warning, Deletion, Entry};
 
 #[derive(Debug)]
 pub struct Group {
 	pub size: u64,
 	pub entries: Vec<Entry>,
+	pub deletions: Vec<Deletion>,
+	/// Files which couldn't be packed completely or changed while they were read
+	pub warnings: Vec<Warning>,
 }
 
-pub fn create_groups(mut index: Vec<Entry>, max_group_size: u64) -> Vec<Group> {
-	index.sort_by(|left, right| right.size.cmp(&left.size));
-	let mut groups: Vec<Group> = Vec::new();
+pub fn create_groups(index: Vec<Entry>, max_group_size: u64) -> Vec<Group> {
+	// files larger than a group are split into parts, which are unpacked in order of their groups,
+	// so full parts get their own groups in front of all others and the last part is packed normally
+	let mut part_groups = Vec::new();
+	let mut entries = Vec::new();
 	
 	for entry in index {
+		if entry.size <= max_group_size {
+			entries.push(entry);
+			continue;
+		}
+		
+		for part in split_entry(entry, max_group_size) {
+			if part.size < max_group_size {
+				entries.push(part);
+				continue;
+			}
+			
+			part_groups.push(Group {
+				size: part.size,
+				entries: vec![part],
+				deletions: Vec::new(),
+				warnings: Vec::new(),
+			});
+		}
+	}
+	
+	entries.sort_by_key(|entry| Reverse(entry.size));
+	let mut groups: Vec<Group> = Vec::new();
+	
+	for entry in entries {
 		let Some(group_position) = groups.iter()
 			.position(|group| group.size + entry.size <= max_group_size)
 		else {
 			groups.push(Group {
 				size: entry.size,
 				entries: vec![entry],
+				deletions: Vec::new(),
+				warnings: Vec::new(),
 			});
 			continue;
 		};
@@ -39,5 +72,76 @@ pub fn create_groups(mut index: Vec<Entry>, max_group_size: u64) -> Vec<Group> {
 		groups.insert(insert_position, group);
 	}
 	
-	groups
+	part_groups.append(&mut groups);
+	part_groups
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{path::PathBuf, time::SystemTime};
+	
+	use crate::Source;
+	
+	use super::*;
+	
+	fn index(sizes: &[u64]) -> Vec<Entry> {
+		let source = Source {
+			id: "source".into(),
+			is_file: false,
+			is_stdin: false,
+			path: PathBuf::from("/source").into(),
+		};
+		
+		sizes.iter()
+			.enumerate()
+			.map(|(i, &size)| Entry {
+				source: source.clone(),
+				path: PathBuf::from(format!("/source/{i}")),
+				size,
+				modified: SystemTime::UNIX_EPOCH,
+				part: None,
+			})
+			.collect()
+	}
+	
+	#[test]
+	fn groups_stay_within_the_limit() {
+		let sizes = [7, 3, 5, 5, 1, 10, 0, 2, 9, 4];
+		let groups = create_groups(index(&sizes), 10);
+		
+		for group in &groups {
+			assert!(group.size <= 10);
+			assert_eq!(group.entries.iter().map(|entry| entry.size).sum::<u64>(), group.size);
+		}
+		
+		assert_eq!(groups.iter().map(|group| group.entries.len()).sum::<usize>(), sizes.len());
+		assert_eq!(groups.iter().map(|group| group.size).sum::<u64>(), sizes.iter().sum::<u64>());
+		// the 46 bytes fill the fewest possible groups
+		assert_eq!(groups.len(), 5);
+	}
+	
+	#[test]
+	fn large_files_are_split_into_parts_in_front() {
+		let groups = create_groups(index(&[25, 3]), 10);
+		
+		let parts: Vec<Vec<(PathBuf, u64, Option<u64>)>> = groups.iter()
+			.map(|group| group.entries.iter()
+				.map(|entry| (entry.path.clone(), entry.size, entry.part.map(|part| part.offset)))
+				.collect())
+			.collect();
+		
+		assert_eq!(parts, [
+			vec![(PathBuf::from("/source/0"), 10, Some(0))],
+			vec![(PathBuf::from("/source/0"), 10, Some(10))],
+			vec![(PathBuf::from("/source/0"), 5, Some(20)), (PathBuf::from("/source/1"), 3, None)],
+		]);
+	}
+	
+	#[test]
+	fn files_as_large_as_a_group_are_not_split() {
+		let groups = create_groups(index(&[10, 10]), 10);
+		
+		assert_eq!(groups.len(), 2);
+		assert!(groups.iter().all(|group| group.entries.len() == 1 && group.entries[0].part.is_none()));
+	}
 }
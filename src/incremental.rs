@@ -0,0 +1,210 @@
+use std::{collections::{HashMap, HashSet}, fs::File, io::{self, Read}, ops::ControlFlow, path::PathBuf, time::UNIX_EPOCH};
+
+use crate::{archive::Archive, crypto::Key, part::Part, Deletion, Entry, Source};
+
+struct FileState {
+	size: u64,
+	mtime: u64,
+	hash: Option<blake3::Hash>,
+	/// Whether the file has a warning, so its packed contents may be incomplete
+	has_warning: bool,
+}
+
+/// State of all files after applying a base archive and its incremental archives
+pub struct PreviousState {
+	files: HashMap<(String, PathBuf), FileState>,
+	compare_contents: bool,
+}
+
+impl PreviousState {
+	/// Replays the given archives in order, starting with the base archive
+	pub fn read(archives: &[PathBuf], key: Key, compare_contents: bool) -> Result<Self, io::Error> {
+		let mut files = HashMap::new();
+		// hashers of files split into parts, until their last part is read
+		let mut part_hashers: HashMap<(String, PathBuf), blake3::Hasher> = HashMap::new();
+		
+		for path in archives {
+			eprintln!("Reading previous archive {}...", path.to_string_lossy());
+			
+			let archive = Archive::new(path.clone(), key);
+			let mut warned = HashSet::new();
+			
+			for sub_archive in archive.sub_archives()? {
+				sub_archive?.for_each_tar(|source_group, tar| {
+					for path in &source_group.deletions {
+						files.remove(&(source_group.id.clone(), path.clone()));
+					}
+					
+					warned.extend(source_group.warnings.iter().map(|(path, _)| (source_group.id.clone(), path.clone())));
+					
+					for entry in tar.entries()? {
+						let mut entry = entry?;
+						let part = Part::read(&mut entry)?;
+						let key = (source_group.id.clone(), entry.path()?.into_owned());
+						let size = entry.header().size()?;
+						let mtime = entry.header().mtime()?;
+						
+						let (size, hash) = match part {
+							None if compare_contents => (size, Some(hash(&mut entry)?)),
+							None => (size, None),
+							Some(part) => {
+								if compare_contents {
+									let hasher = part_hashers.entry(key.clone()).or_default();
+									
+									if part.offset == 0 {
+										hasher.reset();
+									}
+									
+									io::copy(&mut entry, hasher)?;
+								}
+								
+								// the file is only complete after its last part
+								let Some(total_size) = part.total_size
+									.filter(|&total_size| part.offset + size >= total_size)
+								else {
+									continue;
+								};
+								
+								let hash = part_hashers.remove(&key)
+									.map(|hasher| hasher.finalize());
+								(total_size, hash)
+							},
+						};
+						
+						files.insert(key, FileState {
+							size,
+							mtime,
+							hash,
+							has_warning: false,
+						});
+					}
+					
+					Ok(ControlFlow::Continue(()))
+				})?;
+			}
+			
+			// the warnings of an archive apply to the files packed in any of its sub-archives
+			for key in warned {
+				if let Some(previous) = files.get_mut(&key) {
+					previous.has_warning = true;
+				}
+			}
+		}
+		
+		Ok(Self {
+			files,
+			compare_contents,
+		})
+	}
+	
+	/// Filters the index down to new or changed entries and finds all files deleted from the given sources
+	pub fn changes(&self, sources: &[Source], index: Vec<Entry>) -> (Vec<Entry>, Vec<Deletion>) {
+		let mut seen = HashSet::new();
+		let mut changed = Vec::new();
+		
+		for entry in index {
+			let key = (entry.source.id.to_string(), entry.relative_path().to_owned());
+			
+			// data read from stdin can't be compared with the previous archives
+			let is_unchanged = match self.files.get(&key) {
+				Some(_) if entry.source.is_stdin => false,
+				Some(previous) => self.is_unchanged(&entry, previous),
+				None => false,
+			};
+			
+			seen.insert(key);
+			
+			if !is_unchanged {
+				changed.push(entry);
+			}
+		}
+		
+		let mut deletions: Vec<Deletion> = self.files.keys()
+			.filter(|key| !seen.contains(*key))
+			.filter_map(|(source_id, path)| {
+				let source = sources.iter().find(|source| *source.id == **source_id)?;
+				
+				Some(Deletion {
+					source: source.clone(),
+					path: path.clone(),
+				})
+			})
+			.collect();
+		
+		deletions.sort_by(|left, right| left.path.cmp(&right.path));
+		
+		(changed, deletions)
+	}
+	
+	/// Whether the file has the same size and modification time, and the same contents if they are compared
+	///
+	/// A file whose contents can't be read is reported as changed, so the error is handled when it is packed.
+	fn is_unchanged(&self, entry: &Entry, previous: &FileState) -> bool {
+		// files which couldn't be read completely are packed again, even if they didn't change
+		if previous.has_warning {
+			return false;
+		}
+		
+		let mtime = entry.modified.duration_since(UNIX_EPOCH)
+			.map_or(0, |duration| duration.as_secs());
+		
+		if entry.size != previous.size || mtime != previous.mtime {
+			return false;
+		}
+		
+		if !self.compare_contents {
+			return true;
+		}
+		
+		File::open(&entry.path)
+			.and_then(hash)
+			.is_ok_and(|hash| previous.hash == Some(hash))
+	}
+}
+
+fn hash(mut reader: impl Read) -> Result<blake3::Hash, io::Error> {
+	let mut hasher = blake3::Hasher::new();
+	io::copy(&mut reader, &mut hasher)?;
+	Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{path::Path, time::SystemTime};
+	
+	use super::*;
+	
+	#[test]
+	fn unreadable_files_are_changed_when_comparing_contents() {
+		let dir = tempfile::tempdir().unwrap();
+		let source = Source {
+			id: "source".into(),
+			is_file: false,
+			is_stdin: false,
+			path: dir.path().into(),
+		};
+		let modified = SystemTime::now();
+		let entry = Entry {
+			source: source.clone(),
+			path: dir.path().join("vanished"),
+			size: 3,
+			modified,
+			part: None,
+		};
+		
+		let previous = FileState {
+			size: 3,
+			mtime: modified.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+			hash: Some(blake3::hash(b"abc")),
+			has_warning: false,
+		};
+		let state = PreviousState {
+			files: HashMap::from([(("source".to_owned(), Path::new("vanished").to_owned()), previous)]),
+			compare_contents: true,
+		};
+		
+		let (changed, deletions) = state.changes(&[source], vec![entry]);
+		assert_eq!(changed.len(), 1);
+		assert!(deletions.is_empty());
+	}
+}
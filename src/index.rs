@@ -1,52 +1,361 @@
-use std::io;
+use std::{collections::HashSet, ffi::OsStr, fs, io::{self, Read}, os::unix::{ffi::OsStrExt, fs::MetadataExt}, path::{Path, PathBuf}, time::SystemTime};
 
 use walkdir::WalkDir;
 
-use crate::{Entry, Source};
+use crate::{filter::Filter, warning::Warning, Entry, Source};
 
-pub fn create_index(sources: Vec<Source>) -> Result<(Vec<Entry>, u64), io::Error> {
+/// Finds the files of the sources, skipping the files below a source which are excluded by the filter or ignore files
+///
+/// Files and directories below a source which can't be read are returned as warnings if `continue_on_error` is set.
+pub fn create_index(sources: Vec<Source>, filter: &Filter, continue_on_error: bool) -> Result<(Vec<Entry>, Vec<Warning>, u64), io::Error> {
 	let format = humansize::make_format(humansize::BINARY);
 	let mut index = Vec::new();
+	let mut warnings = Vec::new();
 	
 	let mut total_size = 0;
 	let mut prev_index_len = 0;
 	
 	for source in sources {
+		// the size of data read from stdin is only known once it is packed
+		if source.is_stdin {
+			index.push(Entry {
+				path: source.path.to_path_buf(),
+				size: 0,
+				modified: SystemTime::now(),
+				part: None,
+				source,
+			});
+			continue;
+		}
+		
 		if source.is_file {
+			let metadata = source.path.metadata()?;
 			index.push(Entry {
 				path: source.path.to_path_buf(),
-				size: source.path.metadata()?.len(),
+				size: metadata.len(),
+				modified: metadata.modified()?,
+				part: None,
 				source,
 			});
 			continue;
 		}
 		
-		println!("Indexing files in {}...", source.path.to_string_lossy());
+		eprintln!("Indexing files in {}...", source.path.to_string_lossy());
+		
+		let filter = filter.for_source(&source.path);
 		
 		let mut source_size = 0;
+		let mut excluded_files = 0;
+		let mut excluded_dirs = 0;
+		let mut excluded_mounts = 0;
+		let prev_warnings_len = warnings.len();
+		let source_device = fs::metadata(&source.path)?.dev();
+		// patterns of the ignore files in the directories above the current entry, with the depth of their directory,
+		// starting with those above the source which apply to all of its entries
+		let mut ignore_files: Vec<(usize, PathBuf, Filter)> = filter.read_parent_ignore_files(&source.path)?
+			.into_iter()
+			.map(|(directory, ignore_filter)| (0, directory, ignore_filter))
+			.collect();
+		
+		// sorted, so the same files are always packed in the same order
+		let mut walk_dir = WalkDir::new(&source.path).follow_links(true).sort_by_file_name().into_iter();
 		
-		for entry in WalkDir::new(&source.path).follow_links(true) {
-			let entry = entry?;
+		while let Some(entry) = walk_dir.next() {
+			let entry = match entry {
+				Ok(entry) => entry,
+				// the source itself has to be readable
+				Err(err) if continue_on_error && err.depth() > 0 => {
+					let path = err.path().unwrap_or(&source.path).to_owned();
+					let err = err.into_io_error().unwrap_or_else(|| io::Error::other("file system loop found"));
+					warnings.push(skip_unreadable(err, &source, &path, continue_on_error)?);
+					continue;
+				},
+				Err(err) => return Err(err.into()),
+			};
+			let is_dir = entry.file_type().is_dir();
+			
+			while entry.depth() > 0 && ignore_files.last().is_some_and(|(depth, _, _)| *depth >= entry.depth()) {
+				ignore_files.pop();
+			}
+			
+			let is_excluded = entry.depth() > 0 && (
+				is_excluded(&filter, &ignore_files, &source.path, entry.path(), is_dir)
+					|| is_dir && match filter.is_excluded_cache(entry.path()) {
+						Ok(is_cache) => is_cache,
+						Err(err) => {
+							warnings.push(skip_unreadable(err, &source, entry.path(), continue_on_error)?);
+							walk_dir.skip_current_dir();
+							continue;
+						},
+					}
+			);
+			
+			if is_excluded {
+				if is_dir {
+					excluded_dirs += 1;
+					walk_dir.skip_current_dir();
+				} else if entry.file_type().is_file() {
+					excluded_files += 1;
+				}
+				
+				continue;
+			}
+			
+			// only stat directories if their device is checked
+			if is_dir
+				&& entry.depth() > 0
+				&& filter.checks_devices()
+				&& let Some(fs_type) = match entry.metadata() {
+					Ok(metadata) => filter.is_excluded_device(metadata.dev(), source_device),
+					Err(err) => {
+						warnings.push(skip_unreadable(err.into(), &source, entry.path(), continue_on_error)?);
+						walk_dir.skip_current_dir();
+						continue;
+					},
+				}
+			{
+				match fs_type {
+					Some(fs_type) => eprintln!("Skipping {}, which is a mounted {fs_type} file system", entry.path().to_string_lossy()),
+					None => eprintln!("Skipping {}, which is on another file system", entry.path().to_string_lossy()),
+				}
+				
+				excluded_mounts += 1;
+				walk_dir.skip_current_dir();
+				continue;
+			}
+			
+			if is_dir {
+				match filter.read_ignore_files(entry.path()) {
+					Ok(Some(ignore_filter)) => ignore_files.push((entry.depth(), entry.path().to_owned(), ignore_filter)),
+					Ok(None) => (),
+					// the directory is skipped, as its files can't be filtered without its ignore files
+					Err(err) => {
+						warnings.push(skip_unreadable(err, &source, entry.path(), continue_on_error)?);
+						walk_dir.skip_current_dir();
+						continue;
+					},
+				}
+			}
 			
 			if !entry.file_type().is_file() {
 				continue;
 			}
 			
-			let size = entry.metadata()?.len();
+			let metadata = match entry.metadata() {
+				Ok(metadata) => metadata,
+				Err(err) => {
+					warnings.push(skip_unreadable(err.into(), &source, entry.path(), continue_on_error)?);
+					continue;
+				},
+			};
+			let size = metadata.len();
 			source_size += size;
 			
 			index.push(Entry {
 				source: source.clone(),
 				path: entry.path().to_owned(),
-				size: entry.metadata()?.len(),
+				size,
+				modified: metadata.modified()?,
+				part: None,
 			});
 		}
 		
 		total_size += source_size;
 		let files_count = index.len() - prev_index_len;
 		prev_index_len = index.len();
-		println!("Found {files_count} files with a total size of {}.", format(source_size));
+		
+		let mut excluded = Vec::new();
+		
+		if excluded_files > 0 || excluded_dirs > 0 {
+			excluded.push(format!("excluded {excluded_files} files and {excluded_dirs} directories"));
+		}
+		
+		if excluded_mounts > 0 {
+			excluded.push(format!("skipped {excluded_mounts} directories on other file systems"));
+		}
+		
+		if warnings.len() > prev_warnings_len {
+			excluded.push(format!("skipped {} unreadable files and directories", warnings.len() - prev_warnings_len));
+		}
+		
+		if excluded.is_empty() {
+			eprintln!("Found {files_count} files with a total size of {}.", format(source_size));
+		} else {
+			eprintln!("Found {files_count} files with a total size of {}, {}.", format(source_size), excluded.join(", "));
+		}
+	}
+	
+	Ok((index, warnings, total_size))
+}
+
+/// Reads the paths of the files to pack from a file, or stdin if `path` is `-`, one per line or separated by NUL bytes
+///
+/// The paths are made absolute, with their parent directories canonicalized to match the paths of the sources.
+pub fn read_file_list(path: &Path, null_separated: bool) -> Result<Vec<PathBuf>, io::Error> {
+	let mut list = Vec::new();
+	
+	if path == Path::new("-") {
+		io::stdin().lock().read_to_end(&mut list)?;
+	} else {
+		list = fs::read(path)?;
+	}
+	
+	let separator = if null_separated { b'\0' } else { b'\n' };
+	let mut files = Vec::new();
+	
+	for file in list.split(|byte| *byte == separator).filter(|file| !file.is_empty()) {
+		let file = Path::new(OsStr::from_bytes(file));
+		
+		let absolute_path = match (file.parent(), file.file_name()) {
+			(Some(parent), Some(name)) if !parent.as_os_str().is_empty() => parent.canonicalize().map(|parent| parent.join(name)),
+			_ => file.canonicalize(),
+		};
+		
+		files.push(absolute_path.map_err(|err| io::Error::new(
+			err.kind(),
+			format!("failed to find listed file {}: {err}", file.to_string_lossy())
+		))?);
+	}
+	
+	if files.is_empty() {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no files are listed in {}", path.to_string_lossy())));
+	}
+	
+	Ok(files)
+}
+
+/// Returns the deepest directory containing all of the files
+pub fn common_directory(files: &[PathBuf]) -> PathBuf {
+	let mut directory = files.first()
+		.and_then(|file| file.parent())
+		.unwrap_or(Path::new("/"));
+	
+	for file in files {
+		while !file.starts_with(directory) {
+			directory = directory.parent().expect("absolute paths should have the root as a common directory");
+		}
+	}
+	
+	directory.to_owned()
+}
+
+/// Creates the entries of the listed files instead of walking the sources, each file belongs to the deepest source containing it
+///
+/// Listed directories are skipped, and the exclude patterns and ignore files don't apply to the listed files.
+/// Listed files which can't be read are returned as warnings if `continue_on_error` is set.
+pub fn index_files(sources: &[Source], files: Vec<PathBuf>, continue_on_error: bool) -> Result<(Vec<Entry>, Vec<Warning>, u64), io::Error> {
+	let format = humansize::make_format(humansize::BINARY);
+	let mut index = Vec::new();
+	let mut warnings = Vec::new();
+	let mut total_size = 0;
+	let mut skipped = 0;
+	let mut listed = HashSet::new();
+	
+	for source in sources.iter().filter(|source| source.is_stdin) {
+		index.push(Entry {
+			path: source.path.to_path_buf(),
+			size: 0,
+			modified: SystemTime::now(),
+			part: None,
+			source: source.clone(),
+		});
+	}
+	
+	eprintln!("Indexing {} listed files...", files.len());
+	
+	for file in files {
+		if !listed.insert(file.clone()) {
+			continue;
+		}
+		
+		let source = sources.iter()
+			.filter(|source| !source.is_stdin && file.starts_with(&source.path))
+			.max_by_key(|source| source.path.components().count())
+			.ok_or_else(|| io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!("listed file {} is not located in any of the sources", file.to_string_lossy())
+			))?;
+		
+		let metadata = match file.metadata() {
+			Ok(metadata) => metadata,
+			Err(err) if continue_on_error => {
+				let warning = Warning::from_error(err, source, &file, continue_on_error)?;
+				
+				eprintln!("Skipping {}: {}", file.to_string_lossy(), warning.message());
+				warnings.push(warning);
+				continue;
+			},
+			Err(err) => return Err(io::Error::new(
+				err.kind(),
+				format!("failed to read metadata of listed file {}: {err}", file.to_string_lossy())
+			)),
+		};
+		
+		if !metadata.is_file() {
+			skipped += 1;
+			continue;
+		}
+		
+		total_size += metadata.len();
+		
+		index.push(Entry {
+			source: source.clone(),
+			path: file,
+			size: metadata.len(),
+			modified: metadata.modified()?,
+			part: None,
+		});
+	}
+	
+	let files_count = index.iter()
+		.filter(|entry| !entry.source.is_stdin)
+		.count();
+	
+	let mut skipped_entries = Vec::new();
+	
+	if skipped > 0 {
+		skipped_entries.push(format!("skipped {skipped} entries which aren't files"));
+	}
+	
+	if !warnings.is_empty() {
+		skipped_entries.push(format!("skipped {} unreadable files", warnings.len()));
+	}
+	
+	if skipped_entries.is_empty() {
+		eprintln!("Found {files_count} files with a total size of {}.", format(total_size));
+	} else {
+		eprintln!("Found {files_count} files with a total size of {}, {}.", format(total_size), skipped_entries.join(", "));
+	}
+	
+	Ok((index, warnings, total_size))
+}
+
+/// Creates the warning about a file or directory which is skipped because it can't be read, or returns the error
+/// if `continue_on_error` isn't set
+fn skip_unreadable(err: io::Error, source: &Source, path: &Path, continue_on_error: bool) -> Result<Warning, io::Error> {
+	let warning = Warning::from_error(err, source, path, continue_on_error)?;
+	eprintln!("Skipping {}: {}", path.to_string_lossy(), warning.message());
+	Ok(warning)
+}
+
+/// Whether the last matching pattern of the filter and the ignore files excludes the path,
+/// with the patterns of ignore files in deeper directories applied last, unless an include pattern of the filter matches it
+fn is_excluded(filter: &Filter, ignore_files: &[(usize, PathBuf, Filter)], source_path: &Path, path: &Path, is_dir: bool) -> bool {
+	let relative_path = path.strip_prefix(source_path).expect("all entries should be located below the source path");
+	
+	if filter.is_included(relative_path, is_dir) {
+		return false;
+	}
+	
+	let mut is_excluded = filter.is_excluded(relative_path, is_dir);
+	
+	for (_, directory, ignore_filter) in ignore_files {
+		let relative_path = path.strip_prefix(directory).expect("ignore files should only apply to entries below their directory");
+		
+		if let Some(excluded) = ignore_filter.is_excluded(relative_path, is_dir) {
+			is_excluded = Some(excluded);
+		}
 	}
 	
-	Ok((index, total_size))
+	is_excluded.unwrap_or(false)
 }
@@ -1,27 +1,71 @@
 #![forbid(unsafe_code)]
 #![deny(non_snake_case)]
 
-use std::{path::{Path, PathBuf}, sync::Arc};
+use std::{path::{Path, PathBuf}, sync::Arc, time::SystemTime};
+
+use part::Part;
 
 mod index;
 mod group;
+mod incremental;
+mod part;
 mod progress;
 
 mod crypto;
 pub use crypto::{generate_key, Key};
 
+mod compression;
+pub use compression::{Compression, COMPRESSED_EXTENSIONS};
+
+mod segment;
+
+mod warning;
+pub use warning::Warning;
+
+mod filter;
+pub use filter::Preset;
+
+mod output;
+pub use output::remove_partial_output;
+
+mod resume;
+
 mod pack;
-pub use pack::pack;
+pub use pack::{pack, pack_stream, PackOptions};
+
+mod append;
+pub use append::append;
+
+mod remove;
+pub use remove::remove_source;
+
+mod repack;
+pub use repack::{import_tar, repack};
+
+mod export;
+pub use export::export;
 
 mod archive;
 pub use archive::Archive;
 
-const BKY_HEADER: &[u8] = b"backy archive v1\n";
+mod repository;
+pub use repository::{Repository, Snapshot, SnapshotInfo};
+
+const BKY_HEADER: &[u8] = b"backy archive v2\n";
+const BKY_HEADER_V1: &[u8] = b"backy archive v1\n";
+/// Header of single file archives sources were appended to, whose header is stored at the end,
+/// encrypted with the IV of the last append which follows it
+const BKY_HEADER_APPENDED: &[u8] = b"backy appends v2\n";
+
+/// End of the encrypted data of archives with flag 16, which is written last so archives whose writing was interrupted are detected
+const END_MARKER: &[u8] = b"backy archive end\n";
 
 #[derive(Clone, Debug)]
 struct Source {
 	id: Arc<str>,
 	is_file: bool,
+	/// The source is a single file whose data is read from stdin, `path` only contains its name
+	is_stdin: bool,
 	path: Arc<Path>,
 }
 
@@ -29,5 +73,29 @@ struct Source {
 struct Entry {
 	source: Source,
 	path: PathBuf,
+	/// Size of the part if the entry is only a part of a file
 	size: u64,
+	modified: SystemTime,
+	/// Set if the file is split across multiple files of a split archive
+	part: Option<Part>,
+}
+
+impl Entry {
+	/// Path of the entry as it is stored in the archive
+	fn relative_path(&self) -> &Path {
+		let prefix = if self.source.is_file {
+			self.source.path.parent().expect("absolute path to a file should have a parent")
+		} else {
+			&*self.source.path
+		};
+		
+		self.path.strip_prefix(prefix).expect("all entries should be located below the source path")
+	}
+}
+
+/// File which was deleted since the archive an incremental archive is based on
+#[derive(Debug)]
+struct Deletion {
+	source: Source,
+	path: PathBuf,
 }
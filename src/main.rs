@@ -1,11 +1,27 @@
 #![forbid(unsafe_code)]
 #![deny(non_snake_case)]
 
-use std::{fs, io::{self, Write}, os::unix::ffi::OsStrExt, path::PathBuf};
+use std::{env, fs::{self, File}, io::{self, BufReader, BufWriter, Write}, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, process, time::{SystemTime, UNIX_EPOCH}};
 
-use backy::Key;
+use backy::{Compression, Key, PackOptions, Preset};
 use base64::{prelude::BASE64_STANDARD, Engine};
-use clap::{command, Args, Parser, Subcommand};
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+
+/// Parses a source given as NAME=PATH
+fn parse_named_source(arg: &str) -> Result<(String, PathBuf), String> {
+	match arg.split_once('=') {
+		Some((name, path)) if !name.is_empty() && !name.contains('/') && name != "." && name != ".." && !path.is_empty() => {
+			Ok((name.to_owned(), PathBuf::from(path)))
+		},
+		_ => Err("must be NAME=PATH, where NAME is a valid file name".to_owned()),
+	}
+}
+
+/// Exit status if some files couldn't be packed completely because errors were skipped, or changed while they were read
+const PARTIAL_SUCCESS_EXIT_CODE: i32 = 3;
+
+/// Exit status if the process was interrupted by a signal, like a shell reports for SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
 
 fn parse_size(arg: &str) -> Result<u64, parse_size::Error> {
 	parse_size::Config::new()
@@ -14,11 +30,36 @@ fn parse_size(arg: &str) -> Result<u64, parse_size::Error> {
 		.parse_size(arg)
 }
 
-fn parse_compression_level(arg: &str) -> Result<u32, String> {
-	let level: i32 = arg.parse().map_err(|err| format!("{err}"))?;
-	match level {
-		0..=9 => Ok(level as u32),
-		_ => Err("compression-level must be a number from 0 to 9".to_owned()),
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CompressionArg {
+	None,
+	Xz,
+	Zstd,
+	Lz4,
+}
+
+impl From<CompressionArg> for Compression {
+	fn from(arg: CompressionArg) -> Self {
+		match arg {
+			CompressionArg::None => Compression::None,
+			CompressionArg::Xz => Compression::Xz,
+			CompressionArg::Zstd => Compression::Zstd,
+			CompressionArg::Lz4 => Compression::Lz4,
+		}
+	}
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum PresetArg {
+	/// target, node_modules, .venv and __pycache__ directories
+	Dev,
+}
+
+impl From<PresetArg> for Preset {
+	fn from(arg: PresetArg) -> Self {
+		match arg {
+			PresetArg::Dev => Preset::Dev,
+		}
 	}
 }
 
@@ -35,30 +76,313 @@ enum Commands {
 	GenerateKey,
 	/// Create a new backy archive from the given sources
 	Pack(PackArgs),
+	/// Add new sources to an existing backy archive
+	Append(AppendArgs),
+	/// Remove a source from a backy archive, rewriting the files containing it
+	RemoveSource(RemoveSourceArgs),
+	/// Pack an existing backy archive into files of a different size
+	Resplit(ResplitArgs),
+	/// Pack an existing split backy archive into a single file
+	Merge(MergeArgs),
+	/// Write the files of a backy archive as a tar archive, with each source as a top level directory
+	Export(ExportArgs),
+	/// Create a new backy archive from a tar archive, with each top level directory as a source
+	Import(ImportArgs),
 	/// Unpacks a backy archive into its sources
 	Unpack(UnpackArgs),
 	/// Lists all sources contained in a backy archive
 	ListSources(ListSourcesArgs),
 	/// Lists all files contained in a backy archive
 	List(ListArgs),
+	/// Lists the files which couldn't be packed completely into a backy archive or changed while they were read
+	Warnings(WarningsArgs),
 	/// Extracts a single file from the backy archive
 	Get(GetArgs),
+	/// Lists all snapshots contained in a backy repository
+	Snapshots(SnapshotsArgs),
 }
 
 #[derive(Args, Clone, Debug)]
 struct PackArgs {
-	/// All directories / files to include in the backup
-	#[arg(required = true)]
+	/// All directories / files to include in the backup, sources with the same file name are prefixed with their parent directories, like a-data
+	#[arg(required_unless_present_any = ["stdin_name", "source", "files_from"])]
+	sources: Vec<PathBuf>,
+	/// File to write backup data to, - to write it to stdout, or directory to write files to if --size or --repository is specified
+	#[arg(short, long, default_value = "backup.bky")]
+	out: PathBuf,
+	/// Replace an existing archive at --out instead of failing, only once the new one is complete
+	#[arg(long, conflicts_with = "repository")]
+	overwrite: bool,
+	/// Maximum size of files in the out directory, defaults to GiB if no unit is given
+	#[arg(short, long, value_parser = parse_size, conflicts_with = "stdin_name")]
+	size: Option<u64>,
+	/// Apply --size to the compressed and encrypted size of the files instead of the size of the packed files
+	#[arg(long, requires = "size")]
+	limit_output: bool,
+	/// Keep complete files if packing is interrupted and only write the missing ones when run again with the same sources,
+	/// files whose contents changed since then are only replaced with --overwrite
+	#[arg(long, requires = "size", conflicts_with_all = ["limit_output", "repository"])]
+	resume: bool,
+	/// Store the backup as a new snapshot in the deduplicating repository at --out, creating it if necessary
+	#[arg(short, long, conflicts_with_all = ["size", "stdin_name", "continue_on_error"])]
+	repository: bool,
+	#[command(flatten)]
+	common: CommonPackArgs,
+	/// Skip files and directories matching this gitignore style pattern, can be repeated
+	#[arg(short = 'x', long, value_name = "GLOB")]
+	exclude: Vec<String>,
+	/// Pack files and directories matching this pattern even if they match an exclude pattern or ignore file, can be repeated
+	#[arg(long, value_name = "GLOB")]
+	include: Vec<String>,
+	/// Read exclude patterns from this file, one per line, with include patterns starting with !
+	#[arg(long, value_name = "FILE")]
+	exclude_from: Vec<PathBuf>,
+	/// Skip the files and directories of a named set of patterns, can be repeated
+	#[arg(long, value_enum)]
+	preset: Vec<PresetArg>,
+	/// Also skip files and directories matching the patterns of .gitignore files, including those above the sources in their git repository and .git/info/exclude
+	#[arg(long)]
+	respect_gitignore: bool,
+	/// Skip directories containing a CACHEDIR.TAG file
+	#[arg(long)]
+	exclude_caches: bool,
+	/// Skip directories on other file systems than their source, like mount points below it
+	#[arg(long)]
+	one_file_system: bool,
+	/// Also pack /proc, /sys, /dev, /run and /tmp when backing up /
+	#[arg(long)]
+	no_default_excludes: bool,
+	/// Skip mounted file systems of this type, like nfs or proc, can be repeated
+	#[arg(long, value_name = "TYPE", value_delimiter = ',')]
+	exclude_fs_type: Vec<String>,
+	/// Only pack the files listed in this file, - to read the list from stdin, their common directory is the source if none are given
+	#[arg(short = 'T', long, value_name = "FILE", conflicts_with_all = ["exclude", "include", "exclude_from", "preset", "respect_gitignore", "exclude_caches", "one_file_system", "exclude_fs_type"])]
+	files_from: Option<PathBuf>,
+	/// The files in --files-from are separated by NUL bytes instead of newlines, like the output of find -print0
+	#[arg(long, requires = "files_from")]
+	null: bool,
+	/// Only pack files changed since this archive, can be repeated to give a base archive followed by its incremental archives
+	#[arg(short, long, value_name = "ARCHIVE", conflicts_with = "repository")]
+	incremental_from: Vec<PathBuf>,
+	/// Compare the contents of files with unchanged size and modification time when packing incrementally
+	#[arg(long, requires = "incremental_from")]
+	compare_contents: bool,
+	/// Normalize file metadata so packing the same files always results in the same decrypted data, uses SOURCE_DATE_EPOCH as the modification time if set
+	#[arg(long, conflicts_with_all = ["repository", "incremental_from"])]
+	reproducible: bool,
+	/// Derive the IV from the key and the packed data, so packing the same files results in the same encrypted data, can't be used when writing to stdout
+	#[arg(long, requires = "reproducible")]
+	deterministic_iv: bool,
+	/// Key to use for encryption
+	#[arg(short, long, conflicts_with = "key_file")]
+	key: Option<String>,
+	/// File containing the key to use for encryption
+	#[arg(short = 'f', long, conflicts_with = "key")]
+	key_file: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+struct AppendArgs {
+	/// The backy archive to add the sources to, a new file is added if it is a directory
+	archive: PathBuf,
+	/// All directories / files to add, their names must not be used by sources already in the archive
+	#[arg(required_unless_present_any = ["stdin_name", "source"])]
 	sources: Vec<PathBuf>,
+	#[command(flatten)]
+	common: CommonPackArgs,
+	/// Key to use for encryption
+	#[arg(short, long, conflicts_with = "key_file")]
+	key: Option<String>,
+	/// File containing the key to use for encryption
+	#[arg(short = 'f', long, conflicts_with = "key")]
+	key_file: Option<PathBuf>,
+}
+
+/// Arguments shared by the commands packing sources into an archive
+#[derive(Args, Clone, Debug)]
+struct CommonPackArgs {
+	/// Also include the directory / file at PATH as a source named NAME, can be repeated
+	#[arg(long, value_name = "NAME=PATH", value_parser = parse_named_source)]
+	source: Vec<(String, PathBuf)>,
+	/// Compression algorithm to use
+	#[arg(short, long, value_enum, default_value = "xz")]
+	compression: CompressionArg,
+	/// Level of compression to use (xz: 0-9, zstd: 1-22, lz4: 0-12), defaults to 9 for xz, 3 for zstd and 0 for lz4
+	#[arg(short = 'l', long)]
+	compression_level: Option<u32>,
+	/// Number of threads to compress each file with, defaults to the number of available cores
+	#[arg(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+	threads: Option<u32>,
+	/// Additional file extensions to store without compression, on top of common compressed formats
+	#[arg(long = "store", value_name = "EXTENSION", value_delimiter = ',')]
+	store_extensions: Vec<String>,
+	/// Also store files without compression if a sample of their contents looks incompressible
+	#[arg(long)]
+	sample_entropy: bool,
+	/// Compress all files, even ones which are already compressed
+	#[arg(long, conflicts_with_all = ["store_extensions", "sample_entropy"])]
+	compress_all: bool,
+	/// Also back up data read from stdin as a file with this name, for example the output of a database dump
+	#[arg(long, value_name = "NAME")]
+	stdin_name: Option<String>,
+	/// Skip files which can't be read instead of failing, they are listed at the end and recorded in the archive,
+	/// and the exit status is 3 if any were skipped
+	#[arg(long)]
+	continue_on_error: bool,
+	/// Read files which change while they are read again up to this many times, packing the files before them in the same segment again,
+	/// files which still change are listed at the end and recorded in the archive, and the exit status is 3 if there are any,
+	/// can't be used when writing to stdout
+	#[arg(long, value_name = "N", default_value = "0")]
+	retry_changed: u32,
+}
+
+impl CommonPackArgs {
+	/// Options of the shared arguments, with the defaults for all other options
+	fn options(self) -> PackOptions {
+		let compression = Compression::from(self.compression);
+		
+		PackOptions {
+			compression,
+			compression_level: get_compression_level(compression, self.compression_level),
+			threads: self.threads,
+			store_extensions: get_store_extensions(self.compress_all, self.store_extensions),
+			sample_entropy: self.sample_entropy,
+			stdin_name: self.stdin_name,
+			named_sources: self.source,
+			continue_on_error: self.continue_on_error,
+			changed_retries: self.retry_changed,
+			..PackOptions::default()
+		}
+	}
+}
+
+#[derive(Args, Clone, Debug)]
+struct RemoveSourceArgs {
+	/// The backy archive to remove the source from (can be a file or directory)
+	archive: PathBuf,
+	/// The source to remove
+	#[arg(short, long)]
+	source: String,
+	/// Key to use for decryption and encryption
+	#[arg(short, long, conflicts_with = "key_file")]
+	key: Option<String>,
+	/// File containing the key to use for decryption and encryption
+	#[arg(short = 'f', long, conflicts_with = "key")]
+	key_file: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+struct ResplitArgs {
+	/// The backy archive to split (can be a file or directory)
+	archive: PathBuf,
+	/// Directory to write the files of the new archive to
+	#[arg(short, long)]
+	out: PathBuf,
+	/// Replace an existing archive at --out instead of failing, only once the new one is complete
+	#[arg(long)]
+	overwrite: bool,
+	/// Maximum size of files in the out directory, defaults to GiB if no unit is given
+	#[arg(short, long, value_parser = parse_size)]
+	size: u64,
+	/// Compression algorithm to use
+	#[arg(short, long, value_enum, default_value = "xz")]
+	compression: CompressionArg,
+	/// Level of compression to use (xz: 0-9, zstd: 1-22, lz4: 0-12), defaults to 9 for xz, 3 for zstd and 0 for lz4
+	#[arg(short = 'l', long)]
+	compression_level: Option<u32>,
+	/// Number of threads to compress each file with, defaults to the number of available cores
+	#[arg(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+	threads: Option<u32>,
+	/// Additional file extensions to store without compression, on top of common compressed formats
+	#[arg(long = "store", value_name = "EXTENSION", value_delimiter = ',')]
+	store_extensions: Vec<String>,
+	/// Compress all files, even ones which are already compressed
+	#[arg(long, conflicts_with = "store_extensions")]
+	compress_all: bool,
+	/// Key to use for decryption and encryption
+	#[arg(short, long, conflicts_with = "key_file")]
+	key: Option<String>,
+	/// File containing the key to use for decryption and encryption
+	#[arg(short = 'f', long, conflicts_with = "key")]
+	key_file: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+struct MergeArgs {
+	/// The backy archive to merge (can be a file or directory)
+	archive: PathBuf,
+	/// File to write the new archive to
+	#[arg(short, long, default_value = "backup.bky")]
+	out: PathBuf,
+	/// Replace an existing archive at --out instead of failing, only once the new one is complete
+	#[arg(long)]
+	overwrite: bool,
+	/// Compression algorithm to use
+	#[arg(short, long, value_enum, default_value = "xz")]
+	compression: CompressionArg,
+	/// Level of compression to use (xz: 0-9, zstd: 1-22, lz4: 0-12), defaults to 9 for xz, 3 for zstd and 0 for lz4
+	#[arg(short = 'l', long)]
+	compression_level: Option<u32>,
+	/// Number of threads to compress each file with, defaults to the number of available cores
+	#[arg(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+	threads: Option<u32>,
+	/// Additional file extensions to store without compression, on top of common compressed formats
+	#[arg(long = "store", value_name = "EXTENSION", value_delimiter = ',')]
+	store_extensions: Vec<String>,
+	/// Compress all files, even ones which are already compressed
+	#[arg(long, conflicts_with = "store_extensions")]
+	compress_all: bool,
+	/// Key to use for decryption and encryption
+	#[arg(short, long, conflicts_with = "key_file")]
+	key: Option<String>,
+	/// File containing the key to use for decryption and encryption
+	#[arg(short = 'f', long, conflicts_with = "key")]
+	key_file: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+struct ExportArgs {
+	/// The backy archive to export (can be a file or directory)
+	archive: PathBuf,
+	/// File to write the tar archive to, - to write it to stdout
+	#[arg(short, long, default_value = "-")]
+	out: PathBuf,
+	/// Key to use for decryption
+	#[arg(short, long, conflicts_with = "key_file")]
+	key: Option<String>,
+	/// File containing the key to use for decryption
+	#[arg(short = 'f', long, conflicts_with = "key")]
+	key_file: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+struct ImportArgs {
+	/// The tar archive to import, - to read it from stdin
+	tar: PathBuf,
 	/// File to write backup data to, or directory to write files to if --size is specified
 	#[arg(short, long, default_value = "backup.bky")]
 	out: PathBuf,
+	/// Replace an existing archive at --out instead of failing, only once the new one is complete
+	#[arg(long)]
+	overwrite: bool,
 	/// Maximum size of files in the out directory, defaults to GiB if no unit is given
 	#[arg(short, long, value_parser = parse_size)]
 	size: Option<u64>,
-	/// Level of compression to use
-	#[arg(short = 'l', long, value_parser = parse_compression_level, default_value = "9")]
-	compression_level: u32,
+	/// Compression algorithm to use
+	#[arg(short, long, value_enum, default_value = "xz")]
+	compression: CompressionArg,
+	/// Level of compression to use (xz: 0-9, zstd: 1-22, lz4: 0-12), defaults to 9 for xz, 3 for zstd and 0 for lz4
+	#[arg(short = 'l', long)]
+	compression_level: Option<u32>,
+	/// Number of threads to compress each file with, defaults to the number of available cores
+	#[arg(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+	threads: Option<u32>,
+	/// Additional file extensions to store without compression, on top of common compressed formats
+	#[arg(long = "store", value_name = "EXTENSION", value_delimiter = ',')]
+	store_extensions: Vec<String>,
+	/// Compress all files, even ones which are already compressed
+	#[arg(long, conflicts_with = "store_extensions")]
+	compress_all: bool,
 	/// Key to use for encryption
 	#[arg(short, long, conflicts_with = "key_file")]
 	key: Option<String>,
@@ -69,11 +393,15 @@ struct PackArgs {
 
 #[derive(Args, Clone, Debug)]
 struct UnpackArgs {
-	/// The backy archive to unpack (can be a file or directory)
-	archive: PathBuf,
+	/// The backy archive to unpack (can be a file or directory), followed by any incremental archives to apply in order
+	#[arg(required = true)]
+	archives: Vec<PathBuf>,
 	/// Directory to unpack the sources into
 	#[arg(short, long, default_value = ".")]
 	out: PathBuf,
+	/// The snapshot to use if the archive is a repository
+	#[arg(long)]
+	snapshot: Option<String>,
 	/// Key to use for decryption
 	#[arg(short, long, conflicts_with = "key_file")]
 	key: Option<String>,
@@ -86,6 +414,9 @@ struct UnpackArgs {
 struct ListSourcesArgs {
 	/// The backy archive to list sources of (can be a file or directory)
 	archive: PathBuf,
+	/// The snapshot to use if the archive is a repository
+	#[arg(long)]
+	snapshot: Option<String>,
 	/// Key to use for decryption
 	#[arg(short, long, conflicts_with = "key_file")]
 	key: Option<String>,
@@ -101,6 +432,33 @@ struct ListArgs {
 	/// The source containing the files to be listed
 	#[arg(short, long)]
 	source: Option<String>,
+	/// The snapshot to use if the archive is a repository
+	#[arg(long)]
+	snapshot: Option<String>,
+	/// Key to use for decryption
+	#[arg(short, long, conflicts_with = "key_file")]
+	key: Option<String>,
+	/// File containing the key to use for decryption
+	#[arg(short = 'f', long, conflicts_with = "key")]
+	key_file: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+struct WarningsArgs {
+	/// The backy archive to list warnings of (can be a file or directory)
+	archive: PathBuf,
+	/// Key to use for decryption
+	#[arg(short, long, conflicts_with = "key_file")]
+	key: Option<String>,
+	/// File containing the key to use for decryption
+	#[arg(short = 'f', long, conflicts_with = "key")]
+	key_file: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+struct SnapshotsArgs {
+	/// The backy repository to list snapshots of
+	repository: PathBuf,
 	/// Key to use for decryption
 	#[arg(short, long, conflicts_with = "key_file")]
 	key: Option<String>,
@@ -118,6 +476,9 @@ struct GetArgs {
 	/// The source to look for the file in
 	#[arg(short, long)]
 	source: Option<String>,
+	/// The snapshot to use if the archive is a repository
+	#[arg(long)]
+	snapshot: Option<String>,
 	/// Key to use for decryption
 	#[arg(short, long, conflicts_with = "key_file")]
 	key: Option<String>,
@@ -129,6 +490,12 @@ struct GetArgs {
 fn main() {
 	let args = BackyArgs::parse();
 	
+	// partially written archives are removed when interrupted, like when packing fails
+	ctrlc::set_handler(|| {
+		backy::remove_partial_output();
+		process::exit(INTERRUPTED_EXIT_CODE);
+	}).expect("signal handler should only be set once");
+	
 	match args.command {
 		Commands::GenerateKey => {
 			let key = backy::generate_key();
@@ -136,52 +503,308 @@ fn main() {
 			println!("{base64_key}");
 		},
 		Commands::Pack(pack_args) => {
+			let is_stdout = pack_args.out == Path::new("-");
+			
+			if is_stdout && (pack_args.size.is_some() || pack_args.repository || pack_args.deterministic_iv || pack_args.common.retry_changed > 0) {
+				BackyArgs::command()
+					.error(ErrorKind::ArgumentConflict, "--size, --repository, --deterministic-iv and --retry-changed can't be used when writing to stdout")
+					.exit();
+			}
+			
+			if pack_args.files_from.as_deref() == Some(Path::new("-")) && pack_args.common.stdin_name.is_some() {
+				BackyArgs::command()
+					.error(ErrorKind::ArgumentConflict, "--stdin-name can't be used when reading --files-from from stdin")
+					.exit();
+			}
+			
+			let source_date_epoch = match env::var("SOURCE_DATE_EPOCH") {
+				Ok(epoch) if pack_args.reproducible => match epoch.parse() {
+					Ok(epoch) => Some(epoch),
+					Err(err) => {
+						BackyArgs::command()
+							.error(ErrorKind::InvalidValue, format!("SOURCE_DATE_EPOCH must be a unix timestamp: {err}"))
+							.exit();
+					},
+				},
+				_ => None,
+			};
+			
 			let key = get_key(pack_args.key, pack_args.key_file);
-			backy::pack(pack_args.sources, pack_args.out, key, pack_args.size, pack_args.compression_level).unwrap();
+			let options = PackOptions {
+				max_group_size: pack_args.size,
+				limit_output_size: pack_args.limit_output,
+				incremental_from: pack_args.incremental_from,
+				compare_contents: pack_args.compare_contents,
+				reproducible: pack_args.reproducible,
+				source_date_epoch,
+				deterministic_iv: pack_args.deterministic_iv,
+				exclude: pack_args.exclude,
+				include: pack_args.include,
+				exclude_from: pack_args.exclude_from,
+				presets: pack_args.preset.into_iter().map(Preset::from).collect(),
+				respect_gitignore: pack_args.respect_gitignore,
+				exclude_caches: pack_args.exclude_caches,
+				one_file_system: pack_args.one_file_system,
+				exclude_system_dirs: !pack_args.no_default_excludes,
+				exclude_fs_types: pack_args.exclude_fs_type,
+				files_from: pack_args.files_from,
+				null_separated: pack_args.null,
+				overwrite: pack_args.overwrite,
+				resume: pack_args.resume,
+				..pack_args.common.options()
+			};
+			
+			let warnings = if pack_args.repository {
+				let repository = backy::Repository::open_or_create(pack_args.out, key).unwrap();
+				let id = repository.create_snapshot(pack_args.sources, &options).unwrap();
+				println!("Created snapshot {id}");
+				Vec::new()
+			} else if is_stdout {
+				let stdout = BufWriter::new(io::stdout().lock());
+				backy::pack_stream(pack_args.sources, stdout, key, options).unwrap()
+			} else {
+				backy::pack(pack_args.sources, pack_args.out, key, options).unwrap()
+			};
+			
+			if !warnings.is_empty() {
+				process::exit(PARTIAL_SUCCESS_EXIT_CODE);
+			}
+		},
+		Commands::Append(append_args) => {
+			let key = get_key(append_args.key, append_args.key_file);
+			let options = append_args.common.options();
+			
+			let warnings = backy::append(append_args.archive, append_args.sources, key, options).unwrap();
+			
+			if !warnings.is_empty() {
+				process::exit(PARTIAL_SUCCESS_EXIT_CODE);
+			}
+		},
+		Commands::RemoveSource(remove_source_args) => {
+			let key = get_key(remove_source_args.key, remove_source_args.key_file);
+			backy::remove_source(remove_source_args.archive, &remove_source_args.source, key).unwrap();
+		},
+		Commands::Resplit(resplit_args) => {
+			let compression = Compression::from(resplit_args.compression);
+			let compression_level = get_compression_level(compression, resplit_args.compression_level);
+			let store_extensions = get_store_extensions(resplit_args.compress_all, resplit_args.store_extensions);
+			
+			let key = get_key(resplit_args.key, resplit_args.key_file);
+			let options = repack_options(Some(resplit_args.size), compression, compression_level, resplit_args.threads, store_extensions, resplit_args.overwrite);
+			
+			backy::repack(resplit_args.archive, resplit_args.out, key, options).unwrap();
+		},
+		Commands::Merge(merge_args) => {
+			let compression = Compression::from(merge_args.compression);
+			let compression_level = get_compression_level(compression, merge_args.compression_level);
+			let store_extensions = get_store_extensions(merge_args.compress_all, merge_args.store_extensions);
+			
+			let key = get_key(merge_args.key, merge_args.key_file);
+			let options = repack_options(None, compression, compression_level, merge_args.threads, store_extensions, merge_args.overwrite);
+			
+			backy::repack(merge_args.archive, merge_args.out, key, options).unwrap();
+		},
+		Commands::Export(export_args) => {
+			let key = get_key(export_args.key, export_args.key_file);
+			
+			if export_args.out == Path::new("-") {
+				let stdout = BufWriter::new(io::stdout().lock());
+				backy::export(export_args.archive, stdout, key).unwrap();
+			} else {
+				let file = BufWriter::new(File::create_new(export_args.out).unwrap());
+				backy::export(export_args.archive, file, key).unwrap();
+			}
+		},
+		Commands::Import(import_args) => {
+			let compression = Compression::from(import_args.compression);
+			let compression_level = get_compression_level(compression, import_args.compression_level);
+			let store_extensions = get_store_extensions(import_args.compress_all, import_args.store_extensions);
+			
+			let key = get_key(import_args.key, import_args.key_file);
+			let options = repack_options(import_args.size, compression, compression_level, import_args.threads, store_extensions, import_args.overwrite);
+			
+			if import_args.tar == Path::new("-") {
+				let stdin = BufReader::new(io::stdin().lock());
+				backy::import_tar(stdin, import_args.out, key, options).unwrap();
+			} else {
+				let file = BufReader::new(File::open(import_args.tar).unwrap());
+				backy::import_tar(file, import_args.out, key, options).unwrap();
+			}
 		},
 		Commands::Unpack(unpack_args) => {
 			let key = get_key(unpack_args.key, unpack_args.key_file);
-			backy::Archive::new(unpack_args.archive, key).unpack(unpack_args.out).unwrap();
+			
+			if let Some(snapshot) = &unpack_args.snapshot {
+				let [repository] = &unpack_args.archives[..] else {
+					panic!("only one repository can be unpacked");
+				};
+				
+				let repository = backy::Repository::new(repository.clone(), key);
+				repository.snapshot(snapshot).unwrap().unpack(unpack_args.out).unwrap();
+			} else {
+				for archive in unpack_args.archives {
+					backy::Archive::new(archive, key).unpack(unpack_args.out.clone()).unwrap();
+				}
+			}
 		},
 		Commands::ListSources(list_sources_args) => {
 			let key = get_key(list_sources_args.key, list_sources_args.key_file);
-			let archive = backy::Archive::new(list_sources_args.archive, key);
-			for source in archive.sources().unwrap() {
+			
+			let sources = if let Some(snapshot) = &list_sources_args.snapshot {
+				let repository = backy::Repository::new(list_sources_args.archive, key);
+				repository.snapshot(snapshot).unwrap().sources()
+			} else {
+				backy::Archive::new(list_sources_args.archive, key).sources().unwrap()
+			};
+			
+			for source in sources {
 				println!("{source}");
 			}
 		},
-		Commands::List(list_args) => {
-			let key = get_key(list_args.key, list_args.key_file);
-			let archive = backy::Archive::new(list_args.archive, key);
+		Commands::Warnings(warnings_args) => {
+			let key = get_key(warnings_args.key, warnings_args.key_file);
 			
-			if let Some(source) = &list_args.source {
-				if !archive.sources().unwrap().contains(source) {
-					panic!("source {source} is not contained in this archive");
-				}
+			for warning in backy::Archive::new(warnings_args.archive, key).warnings().unwrap() {
+				println!("{warning}");
 			}
+		},
+		Commands::List(list_args) => {
+			let key = get_key(list_args.key, list_args.key_file);
 			
 			let mut stdout = std::io::stdout();
 			let mut writer = stdout.lock();
-			archive.for_each_file(|source, file| {
+			let mut print_file = |source: &str, file: &Path| {
 				if list_args.source.as_ref().is_some_and(|expected| source != expected) {
 					return;
 				}
 				
 				writer.write_all(file.as_os_str().as_bytes()).unwrap();
 				writer.write_all(b"\n").unwrap();
-			}).unwrap();
+			};
+			
+			if let Some(snapshot) = &list_args.snapshot {
+				let repository = backy::Repository::new(list_args.archive, key);
+				let snapshot = repository.snapshot(snapshot).unwrap();
+				
+				if let Some(source) = &list_args.source
+					&& !snapshot.sources().contains(source)
+				{
+					panic!("source {source} is not contained in this snapshot");
+				}
+				
+				snapshot.for_each_file(&mut print_file);
+			} else {
+				let archive = backy::Archive::new(list_args.archive, key);
+				
+				if let Some(source) = &list_args.source
+					&& !archive.sources().unwrap().contains(source)
+				{
+					panic!("source {source} is not contained in this archive");
+				}
+				
+				archive.for_each_file(&mut print_file).unwrap();
+			}
+			
 			stdout.flush().unwrap();
 		},
 		Commands::Get(get_args) => {
 			let key = get_key(get_args.key, get_args.key_file);
-			let archive = backy::Archive::new(get_args.archive, key);
-			
+			let source = get_args.source.as_ref().map(AsRef::as_ref);
 			let stdout = io::stdout().lock();
-			archive.get_file(get_args.source.as_ref().map(AsRef::as_ref), &get_args.path, stdout).unwrap();
+			
+			if let Some(snapshot) = &get_args.snapshot {
+				let repository = backy::Repository::new(get_args.archive, key);
+				repository.snapshot(snapshot).unwrap().get_file(source, &get_args.path, stdout).unwrap();
+			} else {
+				let archive = backy::Archive::new(get_args.archive, key);
+				archive.get_file(source, &get_args.path, stdout).unwrap();
+			}
+		},
+		Commands::Snapshots(snapshots_args) => {
+			let key = get_key(snapshots_args.key, snapshots_args.key_file);
+			let repository = backy::Repository::new(snapshots_args.repository, key);
+			
+			for snapshot in repository.snapshots().unwrap() {
+				println!("{}  {}  {}", snapshot.id, format_time(snapshot.time), snapshot.sources.join(", "));
+			}
 		},
 	}
 }
 
+/// Formats a time as an UTC date and time
+fn format_time(time: SystemTime) -> String {
+	let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+	let days = (seconds / 86400) as i64;
+	let seconds_of_day = seconds % 86400;
+	
+	// convert days since the unix epoch to a civil date
+	let days = days + 719468;
+	let era = days.div_euclid(146097);
+	let day_of_era = days.rem_euclid(146097);
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let month_index = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+	let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+	let year = year_of_era + era * 400 + (month <= 2) as i64;
+	
+	format!(
+		"{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+		seconds_of_day / 3600,
+		seconds_of_day / 60 % 60,
+		seconds_of_day % 60,
+	)
+}
+
+/// Uses the default level of the compression if none is given and exits if the level is invalid
+fn get_compression_level(compression: Compression, compression_level: Option<u32>) -> u32 {
+	let compression_level = compression_level.unwrap_or(compression.default_level());
+	let levels = compression.levels();
+	
+	if !levels.contains(&compression_level) {
+		BackyArgs::command()
+			.error(
+				ErrorKind::ValueValidation,
+				format!("compression-level must be a number from {} to {} for {compression:?}", levels.start(), levels.end())
+			)
+			.exit();
+	}
+	
+	compression_level
+}
+
+/// Extensions of files to store without compression, the common compressed formats followed by the given ones
+fn get_store_extensions(compress_all: bool, store_extensions: Vec<String>) -> Vec<String> {
+	if compress_all {
+		Vec::new()
+	} else {
+		backy::COMPRESSED_EXTENSIONS.iter()
+			.map(|&extension| extension.to_owned())
+			.chain(store_extensions)
+			.collect()
+	}
+}
+
+/// Options for packing the contents of an existing archive again
+fn repack_options(
+	size: Option<u64>,
+	compression: Compression,
+	compression_level: u32,
+	threads: Option<u32>,
+	store_extensions: Vec<String>,
+	overwrite: bool
+) -> PackOptions {
+	PackOptions {
+		max_group_size: size,
+		compression,
+		compression_level,
+		threads,
+		store_extensions,
+		overwrite,
+		..PackOptions::default()
+	}
+}
+
 fn get_key(key_string: Option<String>, key_file: Option<PathBuf>) -> Key {
 	// TODO: handle errors
 	let base64_key = match (key_string, key_file) {
@@ -0,0 +1,218 @@
+use std::{ffi::OsString, fs::{self, File}, io, mem, path::{Path, PathBuf}, sync::{Mutex, MutexGuard, PoisonError}};
+
+/// Cleanups of the output of all unfinished commands, which are done by `remove_partial_output`
+static PARTIAL_OUTPUT: Mutex<Vec<Cleanup>> = Mutex::new(Vec::new());
+
+/// Undoes a change to the file system made while writing output
+#[derive(Clone, Debug, PartialEq)]
+enum Cleanup {
+	RemoveFile(PathBuf),
+	RemoveDir(PathBuf),
+	/// Restores a file which was appended to to its previous length
+	Truncate(PathBuf, u64),
+}
+
+impl Cleanup {
+	fn run(&self) {
+		let _ = match self {
+			Cleanup::RemoveFile(path) => fs::remove_file(path),
+			Cleanup::RemoveDir(path) => fs::remove_dir(path),
+			Cleanup::Truncate(path, len) => File::options().write(true).open(path).and_then(|file| file.set_len(*len)),
+		};
+	}
+}
+
+/// Output of a command, which is removed again if the command fails or is interrupted before it is finished
+///
+/// Files are written to a temporary path next to their final path and only renamed into place once all of them
+/// are complete, so a failed command never leaves a partial archive behind. Existing files are only replaced
+/// if `overwrite` is set, which also removes the files of a previous split archive which aren't replaced.
+#[derive(Debug)]
+pub(crate) struct Output {
+	overwrite: bool,
+	cleanups: Mutex<Vec<Cleanup>>,
+	/// Final paths of the files written to temporary paths
+	files: Mutex<Vec<PathBuf>>,
+	/// Files of the output which are already at their final paths and kept even if the output isn't finished
+	kept_files: Mutex<Vec<PathBuf>>,
+	/// Directories which contain the files of a split archive
+	volume_dirs: Mutex<Vec<PathBuf>>,
+}
+
+impl Output {
+	pub(crate) fn new(overwrite: bool) -> Self {
+		Self {
+			overwrite,
+			cleanups: Mutex::new(Vec::new()),
+			files: Mutex::new(Vec::new()),
+			kept_files: Mutex::new(Vec::new()),
+			volume_dirs: Mutex::new(Vec::new()),
+		}
+	}
+	
+	/// Fails if a file exists at the path and may not be replaced
+	pub(crate) fn check(&self, path: &Path) -> Result<(), io::Error> {
+		if !self.overwrite && fs::symlink_metadata(path).is_ok() {
+			return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path.to_string_lossy())));
+		}
+		
+		Ok(())
+	}
+	
+	/// Creates the directory of a split archive unless it already exists
+	pub(crate) fn create_volume_dir(&self, path: &Path) -> Result<(), io::Error> {
+		let mut partial_output = lock(&PARTIAL_OUTPUT);
+		
+		match fs::create_dir(path) {
+			Ok(()) => self.register(&mut partial_output, Cleanup::RemoveDir(path.to_owned())),
+			Err(err) if err.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => {},
+			Err(err) => return Err(err),
+		}
+		
+		lock(&self.volume_dirs).push(path.to_owned());
+		
+		Ok(())
+	}
+	
+	/// Creates a file which is written to a temporary path until the output is finished
+	pub(crate) fn create_file(&self, path: &Path) -> Result<File, io::Error> {
+		self.check(path)?;
+		
+		let temp_path = temp_path(path);
+		let mut partial_output = lock(&PARTIAL_OUTPUT);
+		
+		// an existing temporary file belongs to another command writing the same file, or was left behind by one which was killed
+		let file = File::options()
+			.read(true)
+			.write(true)
+			.create_new(true)
+			.open(&temp_path)
+			.map_err(|err| match err.kind() {
+				io::ErrorKind::AlreadyExists => io::Error::new(err.kind(), format!(
+					"{} already exists, another command may be writing {}, otherwise it can be removed",
+					temp_path.to_string_lossy(),
+					path.to_string_lossy()
+				)),
+				_ => err,
+			})?;
+		self.register(&mut partial_output, Cleanup::RemoveFile(temp_path));
+		lock(&self.files).push(path.to_owned());
+		
+		Ok(file)
+	}
+	
+	/// Renames a file created by `create_file` to its final path right away, so it is kept even if the output isn't finished
+	pub(crate) fn persist(&self, path: &Path) -> Result<(), io::Error> {
+		self.check(path)?;
+		
+		let cleanup = Cleanup::RemoveFile(temp_path(path));
+		let mut partial_output = lock(&PARTIAL_OUTPUT);
+		
+		fs::rename(temp_path(path), path)?;
+		partial_output.retain(|partial| *partial != cleanup);
+		lock(&self.cleanups).retain(|partial| *partial != cleanup);
+		lock(&self.files).retain(|file| file != path);
+		lock(&self.kept_files).push(path.to_owned());
+		
+		Ok(())
+	}
+	
+	/// Adds an existing file to the output, which is kept when replacing the files of a previous split archive
+	pub(crate) fn keep(&self, path: &Path) {
+		lock(&self.kept_files).push(path.to_owned());
+	}
+	
+	/// Restores the file to its current length unless the output is finished
+	pub(crate) fn restore_len(&self, path: &Path, len: u64) {
+		let mut partial_output = lock(&PARTIAL_OUTPUT);
+		self.register(&mut partial_output, Cleanup::Truncate(path.to_owned(), len));
+	}
+	
+	/// Renames the files to their final paths, returns them
+	pub(crate) fn finish(self) -> Result<Vec<PathBuf>, io::Error> {
+		let files = mem::take(&mut *lock(&self.files));
+		
+		for path in &files {
+			self.check(path)?;
+		}
+		
+		for path in &files {
+			fs::rename(temp_path(path), path)?;
+		}
+		
+		if self.overwrite {
+			let kept_files = lock(&self.kept_files);
+			
+			for dir in lock(&self.volume_dirs).iter() {
+				remove_stale_volumes(dir, &files, &kept_files)?;
+			}
+		}
+		
+		let cleanups = mem::take(&mut *lock(&self.cleanups));
+		lock(&PARTIAL_OUTPUT).retain(|cleanup| !cleanups.contains(cleanup));
+		
+		Ok(files)
+	}
+	
+	fn register(&self, partial_output: &mut Vec<Cleanup>, cleanup: Cleanup) {
+		partial_output.push(cleanup.clone());
+		lock(&self.cleanups).push(cleanup);
+	}
+}
+
+impl Drop for Output {
+	fn drop(&mut self) {
+		let mut partial_output = lock(&PARTIAL_OUTPUT);
+		let cleanups = mem::take(&mut *lock(&self.cleanups));
+		
+		// files are removed before the directories containing them
+		for cleanup in cleanups.iter().rev() {
+			cleanup.run();
+		}
+		
+		partial_output.retain(|cleanup| !cleanups.contains(cleanup));
+	}
+}
+
+/// Removes the partial output of all unfinished commands, for example when the process is interrupted
+///
+/// Meant to be called right before exiting the process, so no more output is created afterwards.
+pub fn remove_partial_output() {
+	let mut partial_output = lock(&PARTIAL_OUTPUT);
+	
+	for cleanup in partial_output.drain(..).rev() {
+		cleanup.run();
+	}
+	
+	// new output of threads still running would be left behind, so it can't be created anymore
+	mem::forget(partial_output);
+}
+
+/// Removes the numbered files of a previous split archive in the directory which weren't replaced
+fn remove_stale_volumes(dir: &Path, files: &[PathBuf], kept_files: &[PathBuf]) -> Result<(), io::Error> {
+	for entry in fs::read_dir(dir)? {
+		let path = entry?.path();
+		
+		let is_volume = path.extension().is_some_and(|extension| extension == "bky")
+			&& path.file_stem().and_then(|stem| stem.to_str()).is_some_and(|stem| stem.parse::<u64>().is_ok());
+		
+		if is_volume && !files.contains(&path) && !kept_files.contains(&path) {
+			fs::remove_file(path)?;
+		}
+	}
+	
+	Ok(())
+}
+
+/// Path a file is written to before it is renamed to `path`
+fn temp_path(path: &Path) -> PathBuf {
+	let mut file_name = OsString::from(".");
+	file_name.push(path.file_name().unwrap_or_default());
+	file_name.push(".tmp");
+	
+	path.with_file_name(file_name)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
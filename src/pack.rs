@@ -1,161 +1,1121 @@
-use std::{fs::{self, File}, io::{self, Seek, Write}, mem, path::{Path, PathBuf}};
+use std::{collections::{HashMap, HashSet, VecDeque}, fs::File, io::{self, Read, Seek, Write}, mem, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, thread, time::{SystemTime, UNIX_EPOCH}};
 
 use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
-use xz2::write::XzEncoder;
+use tar::HeaderMode;
 
-use crate::{crypto::{generate_iv, EncryptWriter, Key, IV}, group::create_groups, index::create_index, progress::{ProgressDisplay, ProgressTracker}, Entry, Source, BKY_HEADER};
+use crate::{archive::{sub_archive::{self, DataLocation}, Archive}, compression::{has_compressed_extension, looks_incompressible, Compression, COMPRESSED_EXTENSIONS}, crypto::{generate_iv, replace_iv, EncryptWriter, Key, IV}, filter::{Filter, Preset}, group::{create_groups, Group}, incremental::PreviousState, index::{common_directory, create_index, index_files, read_file_list}, part::{join_front, split_front, Part}, output::Output, progress::{ProgressDisplay, ProgressTracker}, resume::{self, resume, volume_path}, segment::{Segment, SegmentWriter}, warning::{print_summary, Warning}, Deletion, Entry, Source, BKY_HEADER, END_MARKER};
 
-pub fn pack(sources: Vec<PathBuf>, out: PathBuf, key: Key, max_group_size: Option<u64>, compression_level: u32) -> Result<(), io::Error> {
-	if sources.is_empty() {
-		panic!("at least one source must be provided");
+#[derive(Clone, Debug)]
+pub struct PackOptions {
+	/// Maximum size of each file, everything is packed into a single file if not set
+	pub max_group_size: Option<u64>,
+	/// Apply `max_group_size` to the size of the written files instead of the size of the packed files,
+	/// packing one file after another
+	pub limit_output_size: bool,
+	pub compression: Compression,
+	pub compression_level: u32,
+	/// Number of threads to compress each file with, chosen automatically if not set
+	pub threads: Option<u32>,
+	/// Files with these extensions are stored without compression
+	pub store_extensions: Vec<String>,
+	/// Store files without compression if a sample of their contents looks incompressible
+	pub sample_entropy: bool,
+	/// Base archive followed by its incremental archives, only files changed since then are packed if not empty
+	pub incremental_from: Vec<PathBuf>,
+	/// Compare the contents of files with the same size and modification time to find changes
+	pub compare_contents: bool,
+	/// Normalize file metadata so packing the same files always results in the same decrypted output
+	pub reproducible: bool,
+	/// Modification time to use for all files if `reproducible` is set
+	pub source_date_epoch: Option<u64>,
+	/// Derive the IV from the key and the packed data instead of generating a random one,
+	/// so packing the same files results in the same encrypted output
+	///
+	/// The data is encrypted again with the derived IV once it is written, so it can't be used when packing to a stream.
+	pub deterministic_iv: bool,
+	/// Name of an additional file source whose data is read from stdin
+	pub stdin_name: Option<String>,
+	/// Additional sources with the names to pack them as, instead of the names of their files
+	///
+	/// Sources whose file names are already used are prefixed with the names of their parent directories, like `a-data`.
+	pub named_sources: Vec<(String, PathBuf)>,
+	/// Files and directories below the sources matching these gitignore style patterns are skipped
+	pub exclude: Vec<String>,
+	/// Files and directories matching these patterns are packed even if they match an exclude pattern or ignore file
+	pub include: Vec<String>,
+	/// Files containing exclude patterns, and include patterns starting with `!`, one per line
+	pub exclude_from: Vec<PathBuf>,
+	/// Sets of exclude patterns applied before all other patterns
+	pub presets: Vec<Preset>,
+	/// Also apply the patterns of `.gitignore` files in the directories of the sources and above them up to the root
+	/// of their git repository, and of its `.git/info/exclude`
+	pub respect_gitignore: bool,
+	/// Skip directories marked as caches by a `CACHEDIR.TAG` file
+	pub exclude_caches: bool,
+	/// Skip directories on other file systems than their source
+	pub one_file_system: bool,
+	/// Skip `/proc`, `/sys`, `/dev`, `/run` and `/tmp` when backing up `/`
+	pub exclude_system_dirs: bool,
+	/// Skip directories on other file systems than their source if they have one of these types, like `nfs` or `proc`
+	pub exclude_fs_types: Vec<String>,
+	/// File listing the files to pack instead of walking the directories of the sources, `-` to read the list from stdin
+	///
+	/// Without any sources, the deepest directory containing all listed files becomes the only source.
+	pub files_from: Option<PathBuf>,
+	/// The paths in `files_from` are separated by NUL bytes instead of newlines
+	pub null_separated: bool,
+	/// Skip files which can't be read instead of failing, they are returned and recorded in the archive as warnings
+	pub continue_on_error: bool,
+	/// Number of times a file which changed while it was read is read again,
+	/// files which still change are packed as read and returned and recorded in the archive as warnings,
+	/// can't be used when packing to a stream
+	pub changed_retries: u32,
+	/// Replace existing output files instead of failing, also removing the files of a previous split archive which aren't replaced
+	pub overwrite: bool,
+	/// Keep the files of a split archive which are complete if packing fails, and only write the missing files if the same
+	/// files are packed into the same directory again, using a state file stored in it,
+	/// files whose contents changed since then are only replaced if `overwrite` is set
+	pub resume: bool,
+}
+
+/// Compresses with the default level of xz and stores files with common compressed formats without compression,
+/// without any limits, filters or other changes to what is packed
+impl Default for PackOptions {
+	fn default() -> Self {
+		Self {
+			max_group_size: None,
+			limit_output_size: false,
+			compression: Compression::Xz,
+			compression_level: Compression::Xz.default_level(),
+			threads: None,
+			store_extensions: COMPRESSED_EXTENSIONS.iter()
+				.map(|&extension| extension.to_owned())
+				.collect(),
+			sample_entropy: false,
+			incremental_from: Vec::new(),
+			compare_contents: false,
+			reproducible: false,
+			source_date_epoch: None,
+			deterministic_iv: false,
+			stdin_name: None,
+			named_sources: Vec::new(),
+			exclude: Vec::new(),
+			include: Vec::new(),
+			exclude_from: Vec::new(),
+			presets: Vec::new(),
+			respect_gitignore: false,
+			exclude_caches: false,
+			one_file_system: false,
+			exclude_system_dirs: true,
+			exclude_fs_types: Vec::new(),
+			files_from: None,
+			null_separated: false,
+			continue_on_error: false,
+			changed_retries: 0,
+			overwrite: false,
+			resume: false,
+		}
+	}
+}
+
+/// Packs the sources into an archive, returns the files which couldn't be packed completely if errors are skipped
+/// or which changed while they were read
+pub fn pack(sources: Vec<PathBuf>, out: PathBuf, key: Key, options: PackOptions) -> Result<Vec<Warning>, io::Error> {
+	if options.max_group_size == Some(0) {
+		panic!("max_group_size must be greater than 0");
 	}
 	
-	if compression_level > 9 {
-		panic!("compression_level must be a number between 0 and 9");
+	if options.max_group_size.is_some() && options.stdin_name.is_some() {
+		panic!("the size of data read from stdin isn't known in advance, so it can't be packed into multiple files");
 	}
 	
-	// TODO: get rid of unwraps
-	// TODO: create separate ids for folders with same name
-	let sources: Vec<_> = sources.into_iter()
-		.map(|path| path.canonicalize().unwrap())
-		.map(|path| Source {
-			id: path.file_name().unwrap().to_string_lossy().into(),
-			is_file: path.is_file(),
-			path: path.into(),
-		})
-		.collect();
+	if options.resume && (options.max_group_size.is_none() || options.limit_output_size) {
+		panic!("resume can only be used with max_group_size without limit_output_size, which determines the files in advance");
+	}
 	
-	let is_single_source = sources.len() == 1;
+	let output = Output::new(options.overwrite);
+	
+	// fail before indexing if the archive already exists, unless an interrupted pack into it is resumed
+	if options.max_group_size.is_none() {
+		output.check(&out)?;
+	} else if !options.resume || !resume::has_state(&out) {
+		output.check(&out.join("1.bky"))?;
+	}
 	
-	let (index, total_size) = create_index(sources)?;
+	let (group, is_single_source) = index_sources(sources, key, &options)?;
+	let Group {
+		size: total_size,
+		entries: index,
+		deletions,
+		warnings,
+	} = group;
 	
-	if let Some(max_group_size) = max_group_size {
-		if !out.exists() {
-			fs::create_dir(&out)?;
-		}
+	let available_threads = thread::available_parallelism()
+		.map(|threads| threads.get() as u32)
+		.unwrap_or(1);
+	
+	let warnings = if let Some(max_volume_size) = options.max_group_size
+		&& options.limit_output_size
+	{
+		output.create_volume_dir(&out)?;
 		
-		let groups = create_groups(index, max_group_size);
 		let progress_display = ProgressDisplay::new(total_size);
+		let group = Group {
+			size: total_size,
+			entries: index,
+			deletions,
+			warnings,
+		};
+		
+		let context = PackContext {
+			output: &output,
+			key,
+			options: &options,
+			threads: options.threads.unwrap_or(available_threads),
+			is_single_source,
+		};
+		
+		pack_volumes(&context, &out, group, max_volume_size, progress_display.new_tracker("Total", total_size))?
+	} else if let Some(max_group_size) = options.max_group_size {
+		output.create_volume_dir(&out)?;
+		
+		let mut groups = create_groups(index, max_group_size);
+		
+		// deletions and the files which couldn't be indexed are stored in the first file
+		if groups.is_empty() && (!deletions.is_empty() || !warnings.is_empty()) {
+			groups.push(Group {
+				size: 0,
+				entries: Vec::new(),
+				deletions,
+				warnings,
+			});
+		} else if let Some(group) = groups.first_mut() {
+			group.deletions = deletions;
+			group.warnings = warnings;
+		}
+		
+		let is_packed = if options.resume {
+			resume(&out, &groups, key, &options, is_single_source)?
+		} else {
+			vec![false; groups.len()]
+		};
+		
+		let packed_len = is_packed.iter()
+			.filter(|&&is_packed| is_packed)
+			.count();
 		
-		groups.into_par_iter()
+		if packed_len > 0 {
+			eprintln!("Resuming, {packed_len} of {} files are already complete.", groups.len());
+		}
+		
+		// the number of files is known, so all of them are checked before any is packed
+		for (i, _) in is_packed.iter().enumerate().filter(|&(_, &is_packed)| !is_packed) {
+			output.check(&volume_path(&out, i))?;
+		}
+		
+		let remaining_size = groups.iter()
+			.zip(&is_packed)
+			.filter(|&(_, &is_packed)| !is_packed)
+			.map(|(group, _)| group.size)
+			.sum();
+		let progress_display = ProgressDisplay::new(remaining_size);
+		
+		// groups are already compressed in parallel, so only use the remaining threads within each group
+		let context = PackContext {
+			output: &output,
+			key,
+			options: &options,
+			threads: options.threads.unwrap_or_else(|| (available_threads / (groups.len() - packed_len).max(1) as u32).max(1)),
+			is_single_source,
+		};
+		
+		let warnings = groups.into_par_iter()
+			.zip(is_packed)
 			.enumerate()
-			.map(|(i, group)| -> Result<_, io::Error> {
-				let i = i + 1;
-				let path = out.join(format!("{i}.bky"));
-				pack_group(
-					&path,
-					group.entries,
-					key,
-					compression_level,
-					is_single_source,
-					progress_display.new_tracker(path.to_string_lossy().into_owned(), group.size)
-				)?;
+			.map(|(i, (group, is_packed))| -> Result<_, io::Error> {
+				let path = volume_path(&out, i);
+				
+				// files which are already complete only contribute the warnings stored in them
+				if is_packed {
+					output.keep(&path);
+					return Archive::new(path, key).warnings();
+				}
+				
+				let progress_tracker = progress_display.new_tracker(path.to_string_lossy().into_owned(), group.size);
+				let warnings = pack_group(&context, &path, group, progress_tracker)?;
 				
-				Ok(())
+				// a resumable pack keeps each file once it is complete
+				if options.resume {
+					output.persist(&path)?;
+				}
+				
+				Ok(warnings)
 			})
-			.collect::<Result<(), _>>()?;
+			.collect::<Result<Vec<_>, _>>()?
+			.concat();
+		
+		if options.resume {
+			resume::finish(&out)?;
+		}
+		
+		warnings
 	} else {
 		let progress_display = ProgressDisplay::new(total_size);
-		pack_group(
-			&out,
-			index,
+		let group = Group {
+			size: total_size,
+			entries: index,
+			deletions,
+			warnings,
+		};
+		
+		let context = PackContext {
+			output: &output,
 			key,
-			compression_level,
+			options: &options,
+			threads: options.threads.unwrap_or(available_threads),
 			is_single_source,
-			progress_display.new_tracker("Total", total_size)
-		)?;
+		};
+		
+		pack_group(&context, &out, group, progress_display.new_tracker("Total", total_size))?
+	};
+	
+	output.finish()?;
+	print_summary(&warnings);
+	
+	Ok(warnings)
+}
+
+/// Packs the sources into a single archive written to `writer`, which doesn't need to be seekable
+///
+/// The sizes of the source groups are only known once they are written, so the header is stored at the end of the archive.
+/// Returns the files which couldn't be packed completely if errors are skipped, or which changed while they were read.
+pub fn pack_stream(sources: Vec<PathBuf>, mut writer: impl Write, key: Key, options: PackOptions) -> Result<Vec<Warning>, io::Error> {
+	if options.max_group_size.is_some() {
+		panic!("max_group_size can't be used when packing to a stream");
 	}
 	
-	Ok(())
+	if options.deterministic_iv {
+		panic!("the IV is written before the data when packing to a stream, so it can't be derived from the data");
+	}
+	
+	if options.changed_retries > 0 {
+		panic!("files which change while they are read are packed again by discarding what was written, so changed_retries can't be used when packing to a stream");
+	}
+	
+	let (group, is_single_source) = index_sources(sources, key, &options)?;
+	
+	let available_threads = thread::available_parallelism()
+		.map(|threads| threads.get() as u32)
+		.unwrap_or(1);
+	let threads = encoder_threads(options.threads.unwrap_or(available_threads), &options);
+	
+	let progress_display = ProgressDisplay::new(group.size);
+	let progress_tracker = progress_display.new_tracker("Total", group.size);
+	
+	let mut source_groups = Vec::new();
+	SourceGroup::group_by_source(&mut source_groups, group, &options)?;
+	
+	let iv = generate_iv();
+	
+	writer.write_all(BKY_HEADER)?;
+	writer.write_all(&iv)?;
+	let mut encrypter = EncryptWriter::new(writer, key, iv);
+	
+	// header is stored at the end
+	write_flags(&mut encrypter, &options, is_single_source, 8)?;
+	
+	let mut encrypter = pack_source_groups(encrypter, &mut source_groups, &options, threads, &progress_tracker, |_| {
+		unreachable!("files aren't packed again when packing to a stream")
+	})?;
+	
+	let mut trailer = Vec::new();
+	write_source_groups(&mut trailer, &source_groups)?;
+	let trailer_len: u64 = trailer.len() as u64;
+	
+	encrypter.write_all(&trailer)?;
+	encrypter.write_all(&trailer_len.to_le_bytes())?;
+	encrypter.write_all(END_MARKER)?;
+	encrypter.flush()?;
+	
+	let warnings = collect_warnings(&source_groups);
+	print_summary(&warnings);
+	
+	Ok(warnings)
 }
 
-fn pack_group(
-	out: &Path,
-	entries: Vec<Entry>,
-	key: Key,
-	compression_level: u32,
-	is_single_source: bool,
-	progress_tracker: ProgressTracker
-) -> Result<(), io::Error> {
-	let mut file = File::create_new(out)?;
+/// Indexes the sources and finds the changes since the previous archives if packing incrementally
+pub(crate) fn index_sources(sources: Vec<PathBuf>, key: Key, options: &PackOptions) -> Result<(Group, bool), io::Error> {
+	if sources.is_empty() && options.named_sources.is_empty() && options.stdin_name.is_none() && options.files_from.is_none() {
+		panic!("at least one source must be provided");
+	}
 	
-	file.write_all(BKY_HEADER)?;
+	if options.stdin_name.is_some() && options.files_from.as_deref() == Some(Path::new("-")) {
+		panic!("stdin can only be read once, so stdin_name can't be used when files_from is read from stdin");
+	}
 	
-	let mut source_groups: Vec<(Source, Vec<Entry>, u64)> = Vec::new();
+	if options.reproducible && !options.incremental_from.is_empty() {
+		panic!("reproducible archives don't store the modification times of files, so they can't be compared when packing incrementally");
+	}
 	
-	for entry in entries {
-		match source_groups.iter_mut().find(|(source, _, _)| source.id == entry.source.id) {
-			Some((_, source_entries, _)) => {
-				source_entries.push(entry);
-			},
-			None => {
-				source_groups.push((entry.source.clone(), vec![entry], 0));
-			},
-		}
+	let compression = options.compression;
+	let levels = compression.levels();
+	
+	if !levels.contains(&options.compression_level) {
+		panic!("compression_level must be a number between {} and {} for {compression:?}", levels.start(), levels.end());
 	}
 	
+	let files = read_files_from(options)?;
+	let mut sources = create_sources(sources, files.as_deref(), options);
+	
+	if let Some(name) = &options.stdin_name {
+		sources.push(Source {
+			id: name.as_str().into(),
+			is_file: true,
+			is_stdin: true,
+			path: Path::new(name).into(),
+		});
+	}
+	
+	let is_single_source = sources.len() == 1;
+	
+	let (index, warnings, total_size) = match files {
+		Some(files) => index_files(&sources, files, options.continue_on_error)?,
+		None => create_index(sources.clone(), &create_filter(options)?, options.continue_on_error)?,
+	};
+	
+	let (index, deletions, total_size) = if options.incremental_from.is_empty() {
+		(index, Vec::new(), total_size)
+	} else {
+		let previous_state = PreviousState::read(&options.incremental_from, key, options.compare_contents)?;
+		let (index, mut deletions) = previous_state.changes(&sources, index);
+		
+		// files which couldn't be read still exist, so they keep their previous version
+		deletions.retain(|deletion| !warnings.iter().any(|warning| {
+			warning.source() == &*deletion.source.id && warning.path() == deletion.path
+		}));
+		
+		let total_size = index.iter()
+			.map(|entry| entry.size)
+			.sum();
+		
+		let format = humansize::make_format(humansize::BINARY);
+		eprintln!(
+			"Found {} new or changed files with a total size of {} and {} deleted files.",
+			index.len(),
+			format(total_size),
+			deletions.len(),
+		);
+		
+		(index, deletions, total_size)
+	};
+	
+	let group = Group {
+		size: total_size,
+		entries: index,
+		deletions,
+		warnings,
+	};
+	
+	Ok((group, is_single_source))
+}
+
+/// Settings shared by all files packed into an archive
+#[derive(Clone, Copy)]
+pub(crate) struct PackContext<'a> {
+	pub(crate) output: &'a Output,
+	pub(crate) key: Key,
+	pub(crate) options: &'a PackOptions,
+	/// Number of threads to compress each file with
+	pub(crate) threads: u32,
+	/// Whether the archive contains a single source
+	pub(crate) is_single_source: bool,
+}
+
+/// Packs the group into a new file with the header at its start, returns the files which couldn't be packed completely
+pub(crate) fn pack_group(context: &PackContext, out: &Path, group: Group, progress_tracker: ProgressTracker) -> Result<Vec<Warning>, io::Error> {
+	let PackContext {
+		output,
+		key,
+		options,
+		threads,
+		is_single_source,
+	} = *context;
+	let mut file = output.create_file(out)?;
+	
+	file.write_all(BKY_HEADER)?;
+	
+	let mut source_groups = Vec::new();
+	SourceGroup::group_by_source(&mut source_groups, group, options)?;
+	
+	// a deterministic IV is derived from the data once it is written, until then it is encrypted with a random one
 	let iv = generate_iv();
+	
 	file.write_all(&iv)?;
 	let mut encrypter = EncryptWriter::new(&mut file, key, iv);
 	
-	// skip header
-	let header_size = size_of::<u32>() * 2 + source_groups.iter() //                          source_groups_len(4) + flags(4)
-		.map(|(source, _, _)| size_of::<u32>() * 2 + size_of::<u64>() + source.id.len()) // + sum(id_len(4) + flags(4) + source_len(8) + id)
+	let threads = encoder_threads(threads, options);
+	
+	// skip header, only writing the flags so the archive is recognized as incomplete until the end marker is written
+	let header_size = HEADER_SIZE + source_groups.iter()
+		.map(|group| group.header_size(group.segments_len()))
 		.sum::<usize>();
 	
-	let skip_buffer = vec![0; header_size];
+	write_flags(&mut encrypter, options, is_single_source, 0)?;
+	let skip_buffer = vec![0; header_size - FLAGS_SIZE];
 	encrypter.write_all(&skip_buffer)?;
 	
-	// tar archives
-	let mut encoder = XzEncoder::new(encrypter, compression_level);
-	let mut prev_position = 0;
-	for (source, entries, source_size) in &mut source_groups {
-		let prefix = if source.is_file {
-			source.path.parent().expect("absolute path to a file should have a parent")
-		} else {
-			&*source.path
-		};
+	let mut encrypter = pack_source_groups(encrypter, &mut source_groups, options, threads, &progress_tracker, SegmentWriter::discard_last_segment)?;
+	let data_end = encrypter.stream_position()?;
+	
+	mem::drop(encrypter);
+	
+	// reset file
+	file.seek(io::SeekFrom::Start((BKY_HEADER.len() + size_of::<IV>()) as u64))?;
+	// reset encrypter
+	let mut encrypter = EncryptWriter::new(&mut file, key, iv);
+	
+	let used_header_size = HEADER_SIZE + source_groups.iter()
+		.map(|group| group.header_size(group.segments.len()))
+		.sum::<usize>();
+	
+	if used_header_size == header_size {
+		write_flags(&mut encrypter, options, is_single_source, 0)?;
+		write_source_groups(&mut encrypter, &source_groups)?;
+		encrypter.seek(io::SeekFrom::Start(data_end))?;
+	} else {
+		// warnings recorded while packing don't fit into the reserved space, so the header is stored at the end instead
+		write_flags(&mut encrypter, options, is_single_source, 4 | 8)?;
+		
+		// the length of the padding takes the place of the number of source groups
+		let padding = header_size - HEADER_SIZE;
+		let padding_len: u32 = padding as u32;
+		encrypter.write_all(&padding_len.to_le_bytes())?;
+		encrypter.write_all(&vec![0; padding])?;
 		
-		let mut tar_builder = tar::Builder::new(encoder);
+		let mut trailer = Vec::new();
+		write_source_groups(&mut trailer, &source_groups)?;
+		let trailer_len: u64 = trailer.len() as u64;
+		
+		encrypter.seek(io::SeekFrom::Start(data_end))?;
+		encrypter.write_all(&trailer)?;
+		encrypter.write_all(&trailer_len.to_le_bytes())?;
+	}
+	
+	encrypter.write_all(END_MARKER)?;
+	encrypter.flush()?;
+	
+	// segments discarded to pack changed files again might have been written past the end of the archive
+	let end = encrypter.stream_position()?;
+	mem::drop(encrypter);
+	file.set_len(end)?;
+	
+	if options.deterministic_iv {
+		replace_iv(&mut file, BKY_HEADER.len() as u64, key, iv)?;
+	}
+	
+	Ok(collect_warnings(&source_groups))
+}
+
+/// Writes the tar archives of the source groups and sets their segments
+///
+/// A file which changes while it is read is packed again up to `changed_retries` times. As it can't be removed from
+/// its segment, the segment is discarded with `discard_segment`, the files before it in the segment are packed again,
+/// and the file starts a new segment.
+pub(crate) fn pack_source_groups<W: Write>(
+	mut encrypter: W,
+	source_groups: &mut [SourceGroup],
+	options: &PackOptions,
+	threads: u32,
+	progress_tracker: &ProgressTracker,
+	discard_segment: impl Fn(&mut SegmentWriter<W>) -> Result<(), io::Error>
+) -> Result<W, io::Error> {
+	for group in source_groups {
+		let segment_writer = SegmentWriter::new(
+			encrypter,
+			options.compression,
+			options.compression_level,
+			threads,
+			group.stored_start > 0
+		)?;
+		let mut tar_builder = tar::Builder::new(segment_writer);
+		let mut retries = vec![options.changed_retries; group.entries.len()];
+		let mut segment_start = 0;
+		let mut segment_warnings_len = 0;
+		// the file which changed, which starts a new segment once the files before it are packed again
+		let mut changed = None;
+		let mut i = 0;
 		
-		for entry in entries.iter() {
-			tar_builder.append_file(
-				entry.path.strip_prefix(prefix).expect("all entries should be located below the source path"),
-				&mut File::open(&entry.path)?
-			)?;
+		while let Some(entry) = group.entries.get(i) {
+			let is_changed = changed == Some(i);
 			
-			progress_tracker.advance(entry.size);
+			if (i == group.stored_start && i > 0 || is_changed) && segment_start != i {
+				tar_builder.get_mut().start_segment(i < group.stored_start)?;
+				segment_start = i;
+				segment_warnings_len = group.warnings.len();
+			}
+			
+			if is_changed {
+				changed = None;
+			}
+			
+			if entry.source.is_stdin {
+				append_stdin(&mut tar_builder, entry, options, progress_tracker)?;
+				i += 1;
+				continue;
+			}
+			
+			match append_entry(&mut tar_builder, entry, options, retries[i] > 0)? {
+				Appended::Packed(warning) => {
+					group.warnings.extend(warning);
+					progress_tracker.advance(entry.size);
+					i += 1;
+				},
+				Appended::Changed => {
+					let segment_writer = tar_builder.get_mut();
+					segment_writer.start_segment(i < group.stored_start)?;
+					discard_segment(segment_writer)?;
+					
+					progress_tracker.rewind(group.entries[segment_start..i].iter()
+						.map(|entry| entry.size)
+						.sum());
+					group.warnings.truncate(segment_warnings_len);
+					retries[i] -= 1;
+					changed = Some(i);
+					i = segment_start;
+				},
+			}
 		}
 		
-		encoder = tar_builder.into_inner()?;
+		let (inner, segments) = tar_builder.into_inner()?.finish()?;
+		encrypter = inner;
+		group.segments = segments;
+	}
+	
+	Ok(encrypter)
+}
+
+/// Size of the header without the source groups: flags(4) + compression(1) + source_groups_len(4)
+const HEADER_SIZE: usize = size_of::<u32>() * 2 + size_of::<u8>();
+
+/// Size of the start of the header written by `write_flags`: flags(4) + compression(1)
+const FLAGS_SIZE: usize = size_of::<u32>() + size_of::<u8>();
+
+/// Size of the length of the padding following the header of a volume limited by its output size
+const PADDING_LEN_SIZE: usize = size_of::<u32>();
+
+/// Number of segments space is reserved for in the header of a volume limited by its output size
+const MAX_VOLUME_SEGMENTS: usize = 64;
+
+/// Space reserved for the warnings of files which can't be read or change while packing in the header of a volume limited by its output size
+const MAX_VOLUME_WARNINGS_SIZE: usize = 64 * 1024;
+
+const TAR_BLOCK_SIZE: u64 = 512;
+
+/// Size of the two empty blocks marking the end of a tar archive
+const TAR_END_SIZE: u64 = TAR_BLOCK_SIZE * 2;
+
+/// Smallest part a file is split into to fill up the rest of a volume
+const MIN_PART_SIZE: u64 = 64 * 1024;
+
+/// Fraction of the remaining space of a volume which is filled based on the estimated compression ratio
+const INITIAL_SAFETY: f64 = 0.9;
+
+/// How much more data than the worst case allows is packed at most based on the estimated compression ratio
+const MAX_ESTIMATE_FACTOR: u64 = 16;
+
+/// Packs the entries one volume after another, closing each volume once its compressed and encrypted size reaches `max_volume_size`
+///
+/// The size of compressed data is only known once it is written, so each volume is filled in steps of one segment each.
+/// The amount of data in a step is estimated from the compression ratio so far, a step which turns out too large is discarded
+/// and retried with less data, falling back to the worst case size of the compressed data.
+fn pack_volumes(context: &PackContext, out: &Path, group: Group, max_volume_size: u64, progress_tracker: ProgressTracker) -> Result<Vec<Warning>, io::Error> {
+	let mut source_groups = Vec::new();
+	SourceGroup::group_by_source(&mut source_groups, group, context.options)?;
+	
+	let context = PackContext {
+		threads: encoder_threads(context.threads, context.options),
+		..*context
+	};
+	let mut ratio = CompressionRatio::default();
+	
+	let mut pending: Vec<PendingGroup> = source_groups.into_iter()
+		.map(PendingGroup::from)
+		.collect();
+	
+	let mut number = 1u64;
+	let mut warnings = Vec::new();
+	
+	while !pending.is_empty() {
+		let volume = Volume {
+			path: out.join(format!("{number}.bky")),
+			key: context.key,
+			// a deterministic IV is derived from the data once the volume is written, until then it is encrypted with a random one
+			iv: generate_iv(),
+			max_size: max_volume_size,
+		};
+		
+		let (packed_entries, mut volume_warnings) = pack_volume(&context, &volume, &mut pending, &mut ratio, &progress_tracker)?;
+		warnings.append(&mut volume_warnings);
 		
-		*source_size = encoder.total_in() - prev_position;
-		prev_position = encoder.total_in();
+		// deletions and the warnings of indexing are only stored in the first volume
+		pending.retain(|group| !group.entries.is_empty());
+		
+		if !packed_entries && !pending.is_empty() {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("volume size of {max_volume_size} bytes is too small to pack any files")));
+		}
+		
+		number += 1;
 	}
 	
-	mem::drop(encoder);
+	Ok(warnings)
+}
+
+struct Volume {
+	path: PathBuf,
+	key: Key,
+	iv: IV,
+	max_size: u64,
+}
+
+/// State of the volume currently being packed
+struct VolumeState {
+	/// Number of bytes the compressed data may take up
+	budget: u64,
+	/// Compressed size of the source groups packed so far
+	used: u64,
+	/// Number of segments packed so far, including the finished ones of the current source group
+	segments_len: usize,
+	/// Fraction of the remaining space to fill based on the estimated compression ratio, reduced whenever a step is too large
+	safety: f64,
+	packed_entries: bool,
+}
+
+/// Source group whose entries are packed into consecutive volumes
+struct PendingGroup {
+	source: Source,
+	/// Entries which haven't been packed yet and whether they are stored without compression
+	entries: VecDeque<(Entry, bool)>,
+	deletions: Vec<Deletion>,
+	warnings: Vec<Warning>,
+	/// Number of times each file which changed while it was read has been read again
+	retried: HashMap<PathBuf, u32>,
+}
+
+impl From<SourceGroup> for PendingGroup {
+	fn from(group: SourceGroup) -> Self {
+		let entries = group.entries.into_iter()
+			.enumerate()
+			.map(|(i, entry)| (entry, i >= group.stored_start))
+			.collect();
+		
+		Self {
+			source: group.source,
+			entries,
+			deletions: group.deletions,
+			warnings: group.warnings,
+			retried: HashMap::new(),
+		}
+	}
+}
+
+/// Ratio of the compressed to the uncompressed size of the compressed segments packed so far
+#[derive(Default)]
+struct CompressionRatio {
+	size: u64,
+	compressed_size: u64,
+}
+
+impl CompressionRatio {
+	fn add(&mut self, segment: &Segment) {
+		if segment.compression != Compression::None {
+			self.size += segment.size;
+			self.compressed_size += segment.compressed_size;
+		}
+	}
+	
+	fn estimate(&self) -> Option<f64> {
+		if self.size == 0 || self.compressed_size == 0 {
+			return None;
+		}
+		
+		Some(self.compressed_size as f64 / self.size as f64)
+	}
+}
+
+/// Packs entries into a new volume until it is full or all entries are packed,
+/// returns whether any entries were packed and the files which couldn't be packed completely
+fn pack_volume(
+	context: &PackContext,
+	volume: &Volume,
+	pending: &mut [PendingGroup],
+	ratio: &mut CompressionRatio,
+	progress_tracker: &ProgressTracker
+) -> Result<(bool, Vec<Warning>), io::Error> {
+	let PackContext {
+		output,
+		options,
+		is_single_source,
+		..
+	} = *context;
+	let mut volume_groups: Vec<SourceGroup> = pending.iter_mut()
+		.map(|group| SourceGroup {
+			source: group.source.clone(),
+			entries: Vec::new(),
+			stored_start: 0,
+			segments: Vec::new(),
+			location: None,
+			deletions: mem::take(&mut group.deletions),
+			warnings: mem::take(&mut group.warnings),
+		})
+		.collect();
+	
+	// the segments and warnings are only known once they are written, so space for the largest possible header is reserved
+	let header_size = HEADER_SIZE + PADDING_LEN_SIZE + MAX_VOLUME_SEGMENTS * Segment::HEADER_SIZE + MAX_VOLUME_WARNINGS_SIZE + volume_groups.iter()
+		.map(|group| group.header_size(0))
+		.sum::<usize>();
+	
+	let budget = volume.max_size.checked_sub((BKY_HEADER.len() + size_of::<IV>() + header_size + END_MARKER.len()) as u64)
+		.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("volume size of {} bytes is too small for the header", volume.max_size)))?;
+	
+	let mut file = output.create_file(&volume.path)?;
+	
+	file.write_all(BKY_HEADER)?;
+	file.write_all(&volume.iv)?;
+	let mut encrypter = EncryptWriter::new(&mut file, volume.key, volume.iv);
+	
+	// skip header, only writing the flags so the volume is recognized as incomplete until the end marker is written
+	write_flags(&mut encrypter, options, is_single_source, 4)?;
+	let skip_buffer = vec![0; header_size - FLAGS_SIZE];
+	encrypter.write_all(&skip_buffer)?;
+	
+	let mut state = VolumeState {
+		budget,
+		used: 0,
+		segments_len: 0,
+		safety: INITIAL_SAFETY,
+		packed_entries: false,
+	};
+	
+	for (pending_group, volume_group) in pending.iter_mut().zip(&mut volume_groups) {
+		let is_full;
+		(encrypter, is_full) = pack_volume_group(encrypter, pending_group, volume_group, &mut state, context, ratio, progress_tracker)?;
+		
+		if is_full {
+			break;
+		}
+	}
+	
+	let data_end = encrypter.stream_position()?;
+	mem::drop(encrypter);
+	
+	// discarded steps might have been written past the end of the volume
+	let len = file.stream_position()?;
+	file.set_len(len)?;
+	
+	volume_groups.retain(|group| !group.segments.is_empty() || !group.deletions.is_empty() || !group.warnings.is_empty());
+	
+	let warnings = collect_warnings(&volume_groups);
+	
+	let header_size_of = |volume_groups: &[SourceGroup]| HEADER_SIZE + PADDING_LEN_SIZE + volume_groups.iter()
+		.map(|group| group.header_size(group.segments.len()))
+		.sum::<usize>();
+	
+	// the volume can't grow past its maximum size, so warnings which don't fit are only returned
+	while header_size_of(&volume_groups) > header_size {
+		let group = volume_groups.iter_mut()
+			.rfind(|group| !group.warnings.is_empty())
+			.expect("only warnings should exceed the reserved space");
+		group.warnings.pop();
+	}
+	
+	let stored_warnings = volume_groups.iter()
+		.map(|group| group.warnings.len())
+		.sum::<usize>();
+	
+	if stored_warnings < warnings.len() {
+		eprintln!(
+			"Only {stored_warnings} of {} warnings fit into the header of {}.",
+			warnings.len(),
+			volume.path.to_string_lossy(),
+		);
+	}
+	
+	let used_header_size = header_size_of(&volume_groups);
 	
 	// reset file
 	file.seek(io::SeekFrom::Start((BKY_HEADER.len() + size_of::<IV>()) as u64))?;
 	// reset encrypter
-	let mut encrypter = EncryptWriter::new(&mut file, key, iv);
+	let mut encrypter = EncryptWriter::new(&mut file, volume.key, volume.iv);
+	
+	// header is padded to the reserved size
+	write_flags(&mut encrypter, options, is_single_source, 4)?;
+	write_source_groups(&mut encrypter, &volume_groups)?;
+	
+	let padding = header_size - used_header_size;
+	let padding_len: u32 = padding as u32;
+	encrypter.write_all(&padding_len.to_le_bytes())?;
+	encrypter.write_all(&vec![0; padding])?;
+	
+	encrypter.seek(io::SeekFrom::Start(data_end))?;
+	encrypter.write_all(END_MARKER)?;
+	encrypter.flush()?;
+	
+	if options.deterministic_iv {
+		replace_iv(&mut file, BKY_HEADER.len() as u64, volume.key, volume.iv)?;
+	}
+	
+	Ok((state.packed_entries, warnings))
+}
+
+/// Packs entries of a source group into the volume in steps of one segment each,
+/// returns the writer of the volume and whether the volume is full
+fn pack_volume_group<W: Write + Seek>(
+	encrypter: W,
+	pending_group: &mut PendingGroup,
+	volume_group: &mut SourceGroup,
+	state: &mut VolumeState,
+	context: &PackContext,
+	ratio: &mut CompressionRatio,
+	progress_tracker: &ProgressTracker
+) -> Result<(W, bool), io::Error> {
+	let PackContext {
+		options,
+		threads,
+		..
+	} = *context;
+	let mut encrypter = Some(encrypter);
+	let mut tar_builder: Option<tar::Builder<SegmentWriter<W>>> = None;
+	let mut is_full = false;
+	
+	while let Some(&(_, stored)) = pending_group.entries.front() {
+		// each step adds a segment and the current one is finished at the end of the source group
+		if state.segments_len + 2 > MAX_VOLUME_SEGMENTS {
+			is_full = true;
+			break;
+		}
+		
+		let compression = if stored {
+			Compression::None
+		} else {
+			options.compression
+		};
+		
+		if let Some(tar_builder) = &mut tar_builder
+			&& tar_builder.get_ref().compression() != compression
+		{
+			tar_builder.get_mut().start_segment(!stored)?;
+			state.segments_len += 1;
+			continue;
+		}
+		
+		let group_size: u64 = tar_builder.as_ref()
+			.map_or(0, |tar_builder| tar_builder.get_ref().segments().iter()
+				.map(|segment| segment.compressed_size)
+				.sum());
+		
+		// the end of the tar archive has to fit into the segment following the step
+		let limit = (state.budget - state.used - group_size).saturating_sub(compression.max_compressed_size(TAR_END_SIZE));
+		let worst_case_size = compression.max_uncompressed_size(limit);
+		
+		let step_size = match ratio.estimate() {
+			Some(ratio) if compression != Compression::None => {
+				let estimated_size = (limit as f64 * state.safety / ratio) as u64;
+				estimated_size.min(worst_case_size * MAX_ESTIMATE_FACTOR).max(worst_case_size)
+			},
+			_ => worst_case_size,
+		};
+		
+		let (step, is_split) = plan_step(&mut pending_group.entries, stored, step_size);
+		
+		if step.is_empty() {
+			is_full = true;
+			break;
+		}
+		
+		let tar_builder = match &mut tar_builder {
+			Some(tar_builder) => tar_builder,
+			None => {
+				let segment_writer = SegmentWriter::new(
+					encrypter.take().expect("encrypter should be available until the first step"),
+					options.compression,
+					options.compression_level,
+					threads,
+					!stored
+				)?;
+				tar_builder.insert(tar::Builder::new(segment_writer))
+			},
+		};
+		
+		let mut step_progress = 0;
+		let warnings_len = volume_group.warnings.len();
+		let mut is_changed = false;
+		
+		for entry in &step {
+			let retry = pending_group.retried.get(&entry.path).is_none_or(|&retried| retried < options.changed_retries);
+			
+			match append_entry(tar_builder, entry, options, retry)? {
+				Appended::Packed(warning) => volume_group.warnings.extend(warning),
+				// the step is discarded and packed again, as the file can't be removed from its segment
+				Appended::Changed => {
+					*pending_group.retried.entry(entry.path.clone()).or_default() += 1;
+					is_changed = true;
+					break;
+				},
+			}
+			
+			progress_tracker.advance(entry.size);
+			step_progress += entry.size;
+			
+			// the step is already too large without the data buffered by the encoder
+			if tar_builder.get_ref().segment_written() > limit {
+				break;
+			}
+		}
+		
+		let segment_writer = tar_builder.get_mut();
+		segment_writer.start_segment(!stored)?;
+		let segment = segment_writer.segments().last().expect("step should have finished a segment");
+		
+		if !is_changed && segment.compressed_size <= limit {
+			ratio.add(segment);
+			state.segments_len += 1;
+			state.packed_entries = true;
+			continue;
+		}
+		
+		if !is_changed && step_size == worst_case_size {
+			return Err(io::Error::other("compressed data is larger than its worst case size"));
+		}
+		
+		segment_writer.discard_last_segment()?;
+		progress_tracker.rewind(step_progress);
+		volume_group.warnings.truncate(warnings_len);
+		
+		// a step is only planned smaller if it didn't fit
+		if !is_changed {
+			state.safety /= 2.0;
+		}
+		
+		let mut step = step.into_iter().rev();
+		
+		if is_split {
+			let front = step.next().expect("split step should contain the front of the split entry");
+			let (back, _) = pending_group.entries.pop_front().expect("back of the split entry should be pending");
+			pending_group.entries.push_front((join_front(front, back), stored));
+		}
+		
+		for entry in step {
+			pending_group.entries.push_front((entry, stored));
+		}
+	}
+	
+	let encrypter = match tar_builder {
+		Some(tar_builder) => {
+			let (inner, segments) = tar_builder.into_inner()?.finish()?;
+			state.segments_len += 1;
+			state.used += segments.iter()
+				.map(|segment| segment.compressed_size)
+				.sum::<u64>();
+			volume_group.segments = segments;
+			inner
+		},
+		None => encrypter.expect("encrypter should be available if no step was packed"),
+	};
+	
+	Ok((encrypter, is_full))
+}
+
+/// Takes entries of the same kind from the front of the queue which fit into `size` bytes of a tar archive,
+/// splitting the last one if only a part of it fits, returns them and whether the last one was split
+fn plan_step(entries: &mut VecDeque<(Entry, bool)>, stored: bool, size: u64) -> (Vec<Entry>, bool) {
+	let mut step = Vec::new();
+	let mut step_size = 0;
+	
+	while let Some((entry, entry_stored)) = entries.front() {
+		if *entry_stored != stored {
+			break;
+		}
+		
+		let space = size - step_size;
+		let entry_size = tar_overhead(entry, entry.part.is_some()) + entry.size.next_multiple_of(TAR_BLOCK_SIZE);
+		
+		if entry_size <= space {
+			step_size += entry_size;
+			step.push(entries.pop_front().expect("entry should be pending").0);
+			continue;
+		}
+		
+		let part_size = space.saturating_sub(tar_overhead(entry, true)) / TAR_BLOCK_SIZE * TAR_BLOCK_SIZE;
+		
+		if part_size < MIN_PART_SIZE {
+			break;
+		}
+		
+		let (entry, _) = entries.pop_front().expect("entry should be pending");
+		let (front, back) = split_front(entry, part_size);
+		step.push(front);
+		entries.push_front((back, stored));
+		
+		return (step, true);
+	}
 	
-	let mut flags = 0u32;
+	(step, false)
+}
+
+/// Size of the headers of an entry in a tar archive
+fn tar_overhead(entry: &Entry, is_part: bool) -> u64 {
+	let path_len = entry.relative_path().as_os_str().len() as u64;
+	
+	// gnu headers store long paths in an additional entry
+	let long_path_size = if path_len > 100 {
+		TAR_BLOCK_SIZE + (path_len + 1).next_multiple_of(TAR_BLOCK_SIZE)
+	} else {
+		0
+	};
+	
+	// pax header containing the continuation record
+	let part_size = if is_part {
+		TAR_BLOCK_SIZE * 2
+	} else {
+		0
+	};
+	
+	TAR_BLOCK_SIZE + long_path_size + part_size
+}
+
+/// Writes the flags of the archive, `layout_flags` describe where the header of the source groups is stored
+pub(crate) fn write_flags(mut encrypter: impl Write, options: &PackOptions, is_single_source: bool, layout_flags: u32) -> Result<(), io::Error> {
+	let mut flags = layout_flags;
 	
 	if is_single_source {
 		flags |= 1;
 	}
 	
+	// source groups are split into segments
+	flags |= 2;
+	// the archive ends with the end marker once it is complete
+	flags |= 16;
+	
 	encrypter.write_all(&flags.to_le_bytes())?;
+	encrypter.write_all(&[options.compression.to_byte()])?;
 	
+	Ok(())
+}
+
+pub(crate) fn write_source_groups(mut encrypter: impl Write, source_groups: &[SourceGroup]) -> Result<(), io::Error> {
 	// groups header
 	let groups_len: u32 = source_groups.len() as u32;
 	encrypter.write_all(&groups_len.to_le_bytes())?;
 	
-	for (source, _, source_size) in &source_groups {
+	for group in source_groups {
+		let source = &group.source;
 		let id_len: u32 = source.id.len() as u32;
 		encrypter.write_all(&id_len.to_le_bytes())?;
 		encrypter.write_all(source.id.as_bytes())?;
+		
+		let source_size: u64 = group.segments.iter()
+			.map(|segment| segment.size)
+			.sum();
 		encrypter.write_all(&source_size.to_le_bytes())?;
 		
 		let mut flags = 0u32;
@@ -164,8 +1124,655 @@ fn pack_group(
 			flags |= 1;
 		}
 		
+		if !group.deletions.is_empty() {
+			flags |= 2;
+		}
+		
+		if !group.warnings.is_empty() {
+			flags |= 4;
+		}
+		
+		if group.location.is_some() {
+			flags |= 8;
+		}
+		
 		encrypter.write_all(&flags.to_le_bytes())?;
+		
+		let segments_len: u32 = group.segments.len() as u32;
+		encrypter.write_all(&segments_len.to_le_bytes())?;
+		
+		for segment in &group.segments {
+			segment.write_header(&mut encrypter)?;
+		}
+		
+		if let Some(location) = group.location {
+			location.write(&mut encrypter)?;
+		}
+		
+		if !group.deletions.is_empty() {
+			let deletions_len: u32 = group.deletions.len() as u32;
+			encrypter.write_all(&deletions_len.to_le_bytes())?;
+			
+			for deletion in &group.deletions {
+				let path = deletion.path.as_os_str().as_bytes();
+				let path_len: u32 = path.len() as u32;
+				encrypter.write_all(&path_len.to_le_bytes())?;
+				encrypter.write_all(path)?;
+			}
+		}
+		
+		if !group.warnings.is_empty() {
+			let warnings_len: u32 = group.warnings.len() as u32;
+			encrypter.write_all(&warnings_len.to_le_bytes())?;
+			
+			for warning in &group.warnings {
+				let path = warning.path.as_os_str().as_bytes();
+				let path_len: u32 = path.len() as u32;
+				encrypter.write_all(&path_len.to_le_bytes())?;
+				encrypter.write_all(path)?;
+				
+				let message_len: u32 = warning.message.len() as u32;
+				encrypter.write_all(&message_len.to_le_bytes())?;
+				encrypter.write_all(warning.message.as_bytes())?;
+			}
+		}
 	}
 	
 	Ok(())
 }
+
+/// Returns the warnings of all source groups
+pub(crate) fn collect_warnings(source_groups: &[SourceGroup]) -> Vec<Warning> {
+	source_groups.iter()
+		.flat_map(|group| group.warnings.iter().cloned())
+		.collect()
+}
+
+/// Name of the source backing up `/`, which is unpacked into the out directory itself if it is the only source
+const ROOT_SOURCE_NAME: &str = "root";
+
+/// Creates the filter deciding which files below the sources are packed
+pub(crate) fn create_filter(options: &PackOptions) -> Result<Filter, io::Error> {
+	Ok(Filter::new(&options.presets, &options.exclude, &options.include, &options.exclude_from)?
+		.respect_gitignore(options.respect_gitignore)
+		.exclude_caches(options.exclude_caches)
+		.exclude_file_systems(options.one_file_system, &options.exclude_fs_types)?
+		.exclude_system_dirs(options.exclude_system_dirs))
+}
+
+/// Reads the list of files to pack if the options contain one
+pub(crate) fn read_files_from(options: &PackOptions) -> Result<Option<Vec<PathBuf>>, io::Error> {
+	options.files_from.as_deref()
+		.map(|path| read_file_list(path, options.null_separated))
+		.transpose()
+}
+
+/// Creates the sources of the paths followed by the named sources of the options, with a distinct name for each source
+/// which doesn't depend on the order of the paths
+///
+/// If no sources are given, the listed files get their common directory as a source.
+pub(crate) fn create_sources(mut paths: Vec<PathBuf>, files: Option<&[PathBuf]>, options: &PackOptions) -> Vec<Source> {
+	if let Some(files) = files
+		&& paths.is_empty()
+		&& options.named_sources.is_empty()
+	{
+		paths.push(common_directory(files));
+	}
+	
+	// the given names are reserved first, so names derived from file names never replace them
+	let mut used_names = HashSet::new();
+	
+	for name in options.named_sources.iter().map(|(name, _)| name).chain(&options.stdin_name) {
+		if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+			panic!("source name {name:?} must be a valid file name");
+		}
+		
+		if !used_names.insert(name.clone()) {
+			panic!("source name {name} is given to multiple sources");
+		}
+	}
+	
+	// TODO: get rid of unwraps
+	let paths: Vec<PathBuf> = paths.into_iter()
+		.map(|path| path.canonicalize().unwrap())
+		.collect();
+	
+	let ids = source_ids(&paths, &used_names);
+	
+	let mut sources: Vec<Source> = paths.into_iter()
+		.zip(ids)
+		.map(|(path, id)| {
+			// only the root directory has no name after the path is canonicalized
+			let name = path.file_name().map_or(ROOT_SOURCE_NAME.into(), |name| name.to_string_lossy());
+			
+			if id != name {
+				eprintln!("Packing {} as {id}, because the name {name} is already used by another source", path.to_string_lossy());
+			}
+			
+			Source {
+				id: id.into(),
+				is_file: path.is_file(),
+				is_stdin: false,
+				path: path.into(),
+			}
+		})
+		.collect();
+	
+	sources.extend(options.named_sources.iter().map(|(name, path)| {
+		let path = path.canonicalize().unwrap();
+		
+		Source {
+			id: name.as_str().into(),
+			is_file: path.is_file(),
+			is_stdin: false,
+			path: path.into(),
+		}
+	}));
+	
+	sources
+}
+
+/// Names each source after its file name, prefixed with the names of as many of its parent directories as needed
+/// to distinguish it from the other sources and the used names, so the name doesn't depend on the order of the sources
+fn source_ids(paths: &[PathBuf], used_names: &HashSet<String>) -> Vec<String> {
+	let names: Vec<Vec<String>> = paths.iter()
+		.map(|path| path.iter()
+			.skip(1)
+			.map(|name| name.to_string_lossy().into_owned())
+			.collect())
+		.collect();
+	
+	let id = |names: &[String], depth: usize| match names {
+		[] => ROOT_SOURCE_NAME.to_owned(),
+		names => names[names.len() - depth..].join("-"),
+	};
+	
+	let mut depths = vec![1; paths.len()];
+	
+	loop {
+		let ids: Vec<String> = names.iter()
+			.zip(&depths)
+			.map(|(names, &depth)| id(names, depth))
+			.collect();
+		let mut is_changed = false;
+		let mut conflict = None;
+		
+		for (i, source_id) in ids.iter().enumerate() {
+			let is_used = used_names.contains(source_id) || ids.iter()
+				.enumerate()
+				.any(|(j, other_id)| j != i && other_id == source_id);
+			
+			if !is_used {
+				continue;
+			}
+			
+			if depths[i] < names[i].len() {
+				depths[i] += 1;
+				is_changed = true;
+			} else {
+				conflict = Some(i);
+			}
+		}
+		
+		// a source which can't be prefixed any further might still become distinct once the others are
+		if is_changed {
+			continue;
+		}
+		
+		if let Some(i) = conflict {
+			panic!("source {} can't be given a distinct name, so it has to be named explicitly", paths[i].to_string_lossy());
+		}
+		
+		return ids;
+	}
+}
+
+pub(crate) struct SourceGroup {
+	pub(crate) source: Source,
+	pub(crate) entries: Vec<Entry>,
+	/// Entries starting at this index are stored without compression
+	pub(crate) stored_start: usize,
+	pub(crate) segments: Vec<Segment>,
+	/// Only set for the groups of archives sources were appended to, otherwise the data follows the previous group
+	pub(crate) location: Option<DataLocation>,
+	pub(crate) deletions: Vec<Deletion>,
+	pub(crate) warnings: Vec<Warning>,
+}
+
+impl SourceGroup {
+	pub(crate) fn new(source: Source) -> Self {
+		Self {
+			source,
+			entries: Vec::new(),
+			stored_start: 0,
+			segments: Vec::new(),
+			location: None,
+			deletions: Vec::new(),
+			warnings: Vec::new(),
+		}
+	}
+	
+	fn find_or_insert<'a>(source_groups: &'a mut Vec<SourceGroup>, source: &Source) -> &'a mut SourceGroup {
+		let position = match source_groups.iter().position(|group| group.source.id == source.id) {
+			Some(position) => position,
+			None => {
+				source_groups.push(SourceGroup::new(source.clone()));
+				source_groups.len() - 1
+			},
+		};
+		
+		&mut source_groups[position]
+	}
+	
+	/// Adds the entries and deletions of a group to the groups of their sources,
+	/// with entries that should be stored uncompressed moved to the end of each group
+	pub(crate) fn group_by_source(source_groups: &mut Vec<SourceGroup>, group: Group, options: &PackOptions) -> Result<(), io::Error> {
+		for entry in group.entries {
+			SourceGroup::find_or_insert(source_groups, &entry.source).entries.push(entry);
+		}
+		
+		for deletion in group.deletions {
+			SourceGroup::find_or_insert(source_groups, &deletion.source).deletions.push(deletion);
+		}
+		
+		for warning in group.warnings {
+			SourceGroup::find_or_insert(source_groups, &warning.source).warnings.push(warning);
+		}
+		
+		for group in source_groups {
+			let mut compressed_entries = Vec::new();
+			let mut stored_entries = Vec::new();
+			
+			for entry in group.entries.drain(..) {
+				if is_stored(&entry, options) {
+					stored_entries.push(entry);
+				} else {
+					compressed_entries.push(entry);
+				}
+			}
+			
+			group.stored_start = compressed_entries.len();
+			group.entries = compressed_entries;
+			group.entries.append(&mut stored_entries);
+		}
+		
+		Ok(())
+	}
+	
+	/// Size of the header of the group: id_len(4) + flags(4) + source_len(8) + segments_len(4) + id + segments + deletions + warnings
+	fn header_size(&self, segments_len: usize) -> usize {
+		size_of::<u32>() * 3 + size_of::<u64>() + self.source.id.len() + segments_len * Segment::HEADER_SIZE
+			+ self.location.map_or(0, |_| DataLocation::SIZE) + self.deletions_size() + self.warnings_size()
+	}
+	
+	fn segments_len(&self) -> usize {
+		let compressed_segment = self.stored_start > 0;
+		let stored_segment = self.stored_start < self.entries.len();
+		
+		// groups without entries still contain the end of the tar archive
+		(compressed_segment as usize + stored_segment as usize).max(1)
+	}
+	
+	fn deletions_size(&self) -> usize {
+		if self.deletions.is_empty() {
+			return 0;
+		}
+		
+		size_of::<u32>() + self.deletions.iter() // deletions_len(4)
+			.map(|deletion| size_of::<u32>() + deletion.path.as_os_str().len()) // + sum(path_len(4) + path)
+			.sum::<usize>()
+	}
+	
+	fn warnings_size(&self) -> usize {
+		if self.warnings.is_empty() {
+			return 0;
+		}
+		
+		size_of::<u32>() + self.warnings.iter() // warnings_len(4)
+			.map(|warning| size_of::<u32>() * 2 + warning.path.as_os_str().len() + warning.message.len()) // + sum(path_len(4) + path + message_len(4) + message)
+			.sum::<usize>()
+	}
+}
+
+/// Converts a source group read from an archive, only its header can be written again
+impl From<sub_archive::SourceGroup> for SourceGroup {
+	fn from(group: sub_archive::SourceGroup) -> Self {
+		let source = Source {
+			id: group.id.as_str().into(),
+			is_file: group.flags & 1 != 0,
+			is_stdin: false,
+			path: Path::new(&group.id).into(),
+		};
+		
+		let deletions = group.deletions.into_iter()
+			.map(|path| Deletion {
+				source: source.clone(),
+				path,
+			})
+			.collect();
+		
+		let warnings = group.warnings.into_iter()
+			.map(|(path, message)| Warning::new(source.clone(), path, message))
+			.collect();
+		
+		SourceGroup {
+			source,
+			entries: Vec::new(),
+			stored_start: 0,
+			segments: group.segments,
+			// the data is written again after the previous group, unless the location is set again
+			location: None,
+			deletions,
+			warnings,
+		}
+	}
+}
+
+/// The multithreaded encoders produce different output than the single threaded ones,
+/// so always use them for reproducible output independent of the number of threads
+pub(crate) fn encoder_threads(threads: u32, options: &PackOptions) -> u32 {
+	if threads == 1 && !options.reproducible {
+		0
+	} else {
+		threads
+	}
+}
+
+/// Maximum size of the parts data read from stdin is packed in, which are buffered in memory
+const STDIN_PART_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Packs data read from stdin in parts of at most `STDIN_PART_SIZE` bytes,
+/// as the size of each tar entry has to be known before its data
+fn append_stdin<W: Write>(
+	tar_builder: &mut tar::Builder<W>,
+	entry: &Entry,
+	options: &PackOptions,
+	progress_tracker: &ProgressTracker
+) -> Result<(), io::Error> {
+	let mut stdin = io::stdin().lock();
+	let mut buffer = Vec::new();
+	let mut offset = 0;
+	
+	loop {
+		buffer.clear();
+		(&mut stdin).take(STDIN_PART_SIZE).read_to_end(&mut buffer)?;
+		
+		let size = buffer.len() as u64;
+		let is_last = size < STDIN_PART_SIZE;
+		
+		// data which fits into a single part is stored like a regular file
+		if !is_last || offset > 0 {
+			let part = Part {
+				offset,
+				total_size: is_last.then_some(offset + size),
+			};
+			part.write_record(tar_builder)?;
+		}
+		
+		let mut header = tar::Header::new_gnu();
+		header.set_size(size);
+		header.set_mode(0o644);
+		
+		let mtime = if options.reproducible {
+			options.source_date_epoch.unwrap_or(0)
+		} else {
+			entry.modified.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+		};
+		header.set_mtime(mtime);
+		
+		tar_builder.append_data(&mut header, entry.relative_path(), &buffer[..])?;
+		
+		progress_tracker.extend(size);
+		progress_tracker.advance(size);
+		
+		if is_last {
+			return Ok(());
+		}
+		
+		offset += size;
+	}
+}
+
+/// Outcome of appending the file of an entry to a tar archive
+enum Appended {
+	/// The file was packed, with a warning if it couldn't be read completely or changed while it was read
+	Packed(Option<Warning>),
+	/// The file changed while it was read, so it has to be discarded and packed again
+	Changed,
+}
+
+/// Appends the file of an entry to the tar archive, with a warning if the file changed while it was read,
+/// or instead of failing if the file can't be read and errors are skipped
+///
+/// A file which can't be opened is left out, while the data of a file which fails or ends early while it is read
+/// is filled up with zeros to the size stored in its header. If `retry` is set, a file whose size or modification time
+/// changes while it is read is returned as changed instead, so the caller can discard it and pack it again.
+fn append_entry<W: Write>(tar_builder: &mut tar::Builder<W>, entry: &Entry, options: &PackOptions, retry: bool) -> Result<Appended, io::Error> {
+	let opened = open_entry(entry).and_then(|file| {
+		let header = entry_header(entry, file.get_ref(), options)?;
+		let state = file_state(file.get_ref())?;
+		Ok((file, header, state))
+	});
+	
+	let (file, mut header, state) = match opened {
+		Ok(opened) => opened,
+		Err(err) => return Warning::from_error(err, &entry.source, &entry.path, options.continue_on_error).map(|warning| Appended::Packed(Some(warning))),
+	};
+	
+	let mut reader = FillReader::new(file, header.size()?);
+	
+	if let Some(part) = entry.part {
+		part.write_record(tar_builder)?;
+	}
+	
+	tar_builder.append_data(&mut header, entry.relative_path(), &mut reader)?;
+	let is_changed = file_state(reader.inner.get_ref())? != state;
+	
+	if is_changed && retry {
+		eprintln!("{} changed while it was read, reading it again.", entry.path.to_string_lossy());
+		return Ok(Appended::Changed);
+	}
+	
+	let warning = match reader.error {
+		// the file got shorter while it was read
+		Some(err) if is_changed && err.kind() == io::ErrorKind::UnexpectedEof => Some(Warning::for_file(
+			&entry.source,
+			&entry.path,
+			"changed while it was read and ended early, the rest is filled with zeros".to_owned(),
+		)),
+		Some(err) if options.continue_on_error => {
+			let err = io::Error::new(err.kind(), format!("{err}, the rest is filled with zeros"));
+			Some(Warning::from_error(err, &entry.source, &entry.path, options.continue_on_error)?)
+		},
+		Some(err) => return Err(io::Error::new(err.kind(), format!("failed to read {}: {err}", entry.path.to_string_lossy()))),
+		None if is_changed => Some(Warning::for_file(&entry.source, &entry.path, "changed while it was read".to_owned())),
+		None => None,
+	};
+	
+	Ok(Appended::Packed(warning))
+}
+
+/// Size and modification time of a file, which are compared before and after reading it to detect changes
+fn file_state(file: &File) -> Result<(u64, Option<SystemTime>), io::Error> {
+	let metadata = file.metadata()?;
+	Ok((metadata.len(), metadata.modified().ok()))
+}
+
+/// Reads exactly `size` bytes, filling the rest with zeros once reading fails or the data ends early,
+/// so the size of a tar entry always matches its header
+struct FillReader<R: Read> {
+	inner: R,
+	remaining: u64,
+	error: Option<io::Error>,
+}
+
+impl<R: Read> FillReader<R> {
+	fn new(inner: R, size: u64) -> Self {
+		Self {
+			inner,
+			remaining: size,
+			error: None,
+		}
+	}
+}
+
+impl<R: Read> Read for FillReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let len = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+		let buf = &mut buf[..len];
+		
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		
+		let read = if self.error.is_some() {
+			0
+		} else {
+			match self.inner.read(buf) {
+				Ok(0) => {
+					self.error = Some(io::Error::new(io::ErrorKind::UnexpectedEof, "file ended early"));
+					0
+				},
+				Ok(read) => read,
+				Err(err) if err.kind() == io::ErrorKind::Interrupted => return Err(err),
+				Err(err) => {
+					self.error = Some(err);
+					0
+				},
+			}
+		};
+		
+		let len = if read == 0 {
+			buf.fill(0);
+			buf.len()
+		} else {
+			read
+		};
+		
+		self.remaining -= len as u64;
+		Ok(len)
+	}
+}
+
+/// Opens the file of an entry, limited to the part of the file if it is split
+fn open_entry(entry: &Entry) -> Result<io::Take<File>, io::Error> {
+	let mut file = File::open(&entry.path)?;
+	
+	let Some(part) = entry.part else {
+		return Ok(file.take(u64::MAX));
+	};
+	
+	file.seek(io::SeekFrom::Start(part.offset))?;
+	Ok(file.take(entry.size))
+}
+
+fn entry_header(entry: &Entry, file: &File, options: &PackOptions) -> Result<tar::Header, io::Error> {
+	let mut header = tar::Header::new_gnu();
+	
+	if options.reproducible {
+		header.set_metadata_in_mode(&file.metadata()?, HeaderMode::Deterministic);
+		
+		if let Some(source_date_epoch) = options.source_date_epoch {
+			header.set_mtime(source_date_epoch);
+		}
+	} else {
+		header.set_metadata(&file.metadata()?);
+	}
+	
+	if entry.part.is_some() {
+		header.set_size(entry.size);
+	}
+	
+	Ok(header)
+}
+
+/// Whether the file of the entry is stored without compression
+///
+/// A file which can't be sampled is compressed, the error is only reported once it is packed.
+pub(crate) fn is_stored(entry: &Entry, options: &PackOptions) -> bool {
+	if options.compression == Compression::None {
+		return false;
+	}
+	
+	if has_compressed_extension(&entry.path, &options.store_extensions) {
+		return true;
+	}
+	
+	// data read from stdin can only be read once
+	if options.sample_entropy && entry.size > 0 && !entry.source.is_stdin {
+		return looks_incompressible(&entry.path).unwrap_or(false);
+	}
+	
+	false
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{fs, thread, time::{Duration, Instant}};
+	
+	use crate::crypto::generate_key;
+	
+	use super::*;
+	
+	#[test]
+	fn retried_file_which_gets_shorter_leaves_no_stale_data() {
+		let dir = tempfile::tempdir().unwrap();
+		let source = dir.path().join("source");
+		let path = source.join("file");
+		fs::create_dir(&source).unwrap();
+		fs::write(&path, vec![1; 16 << 20]).unwrap();
+		
+		// the file keeps changing while it is read at first, so it is discarded and packed again once it got shorter
+		let file = File::options().write(true).open(&path).unwrap();
+		let changer = thread::spawn(move || {
+			let start = Instant::now();
+			
+			while start.elapsed() < Duration::from_millis(300) {
+				file.set_len((16 << 20) + 1).unwrap();
+				file.set_len(16 << 20).unwrap();
+			}
+			
+			file.set_len(1024).unwrap();
+		});
+		
+		let key = generate_key();
+		let out = dir.path().join("out.bky");
+		let options = PackOptions {
+			compression: Compression::None,
+			compression_level: Compression::None.default_level(),
+			changed_retries: u32::MAX,
+			..PackOptions::default()
+		};
+		
+		pack(vec![source], out.clone(), key, options).unwrap();
+		changer.join().unwrap();
+		
+		let unpacked = dir.path().join("unpacked");
+		Archive::new(out, key).unpack(unpacked.clone()).unwrap();
+		assert_eq!(fs::read(unpacked.join("file")).unwrap(), vec![1; 1024]);
+	}
+	
+	#[test]
+	fn files_which_can_not_be_sampled_are_compressed() {
+		let dir = tempfile::tempdir().unwrap();
+		let source = Source {
+			id: "source".into(),
+			is_file: false,
+			is_stdin: false,
+			path: dir.path().into(),
+		};
+		let entry = Entry {
+			source,
+			path: dir.path().join("vanished"),
+			size: 1024,
+			modified: SystemTime::now(),
+			part: None,
+		};
+		let options = PackOptions {
+			sample_entropy: true,
+			..PackOptions::default()
+		};
+		
+		assert!(!is_stored(&entry, &options));
+	}
+}
@@ -0,0 +1,239 @@
+use std::io::{self, Read, Write};
+
+use crate::Entry;
+
+const OFFSET_KEY: &str = "BACKY.part.offset";
+const TOTAL_SIZE_KEY: &str = "BACKY.part.total_size";
+
+/// Range of a file which is too large to fit into a single file of a split archive
+///
+/// Parts are stored as regular tar entries preceded by a pax header containing the offset and the size of the whole file.
+/// Data read from stdin is stored in parts as well, with the size of the whole file only stored in its last part.
+#[derive(Clone, Copy, Debug)]
+pub struct Part {
+	pub offset: u64,
+	/// Size of the whole file, not known yet for parts of data read from stdin other than the last one
+	pub total_size: Option<u64>,
+}
+
+impl Part {
+	/// Reads the continuation record of a tar entry, returns `None` if the entry contains a whole file
+	pub fn read(entry: &mut tar::Entry<impl Read>) -> Result<Option<Self>, io::Error> {
+		let Some(extensions) = entry.pax_extensions()? else {
+			return Ok(None);
+		};
+		
+		let mut offset = None;
+		let mut total_size = None;
+		
+		for extension in extensions {
+			let extension = extension?;
+			
+			let value = match extension.key() {
+				Ok(OFFSET_KEY) => &mut offset,
+				Ok(TOTAL_SIZE_KEY) => &mut total_size,
+				_ => continue,
+			};
+			
+			*value = extension.value().ok()
+				.and_then(|value| value.parse::<u64>().ok());
+			
+			if value.is_none() {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid continuation record"));
+			}
+		}
+		
+		match (offset, total_size) {
+			(Some(offset), total_size) => Ok(Some(Self {
+				offset,
+				total_size,
+			})),
+			(None, None) => Ok(None),
+			_ => Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete continuation record")),
+		}
+	}
+	
+	/// Writes the continuation record for the next entry
+	pub fn write_record<W: Write>(&self, builder: &mut tar::Builder<W>) -> Result<(), io::Error> {
+		let mut data = Vec::new();
+		
+		let records = [(OFFSET_KEY, Some(self.offset)), (TOTAL_SIZE_KEY, self.total_size)];
+		
+		for (key, value) in records.into_iter().filter_map(|(key, value)| Some((key, value?))) {
+			let record = format!(" {key}={value}\n");
+			// the length includes its own digits
+			let mut len = record.len();
+			while len.to_string().len() + record.len() != len {
+				len = len.to_string().len() + record.len();
+			}
+			
+			write!(data, "{len}{record}")?;
+		}
+		
+		let mut header = tar::Header::new_ustar();
+		header.set_entry_type(tar::EntryType::XHeader);
+		header.set_size(data.len() as u64);
+		header.set_mode(0o644);
+		
+		builder.append_data(&mut header, "PaxHeaders/part", &data[..])
+	}
+}
+
+/// Splits an entry which is larger than `part_size` into consecutive parts of that size
+pub fn split_entry(entry: Entry, part_size: u64) -> Vec<Entry> {
+	let mut parts = Vec::new();
+	let mut offset = 0;
+	
+	while offset < entry.size {
+		parts.push(Entry {
+			source: entry.source.clone(),
+			path: entry.path.clone(),
+			size: part_size.min(entry.size - offset),
+			modified: entry.modified,
+			part: Some(Part {
+				offset,
+				total_size: Some(entry.size),
+			}),
+		});
+		
+		offset += part_size;
+	}
+	
+	parts
+}
+
+/// Splits the first `size` bytes off an entry, which continues in the second returned entry
+pub fn split_front(entry: Entry, size: u64) -> (Entry, Entry) {
+	let part = entry.part.unwrap_or(Part {
+		offset: 0,
+		total_size: Some(entry.size),
+	});
+	
+	let front = Entry {
+		source: entry.source.clone(),
+		path: entry.path.clone(),
+		size,
+		modified: entry.modified,
+		part: Some(part),
+	};
+	
+	let back = Entry {
+		size: entry.size - size,
+		part: Some(Part {
+			offset: part.offset + size,
+			total_size: part.total_size,
+		}),
+		..entry
+	};
+	
+	(front, back)
+}
+
+/// Joins an entry split by `split_front` back together
+pub fn join_front(front: Entry, back: Entry) -> Entry {
+	let size = front.size + back.size;
+	let part = front.part.filter(|part| part.offset > 0 || part.total_size.is_none_or(|total_size| size < total_size));
+	
+	Entry {
+		size,
+		part,
+		..front
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{path::PathBuf, time::SystemTime};
+	
+	use crate::Source;
+	
+	use super::*;
+	
+	fn part(offset: u64, total_size: Option<u64>) -> Option<Part> {
+		Some(Part {
+			offset,
+			total_size,
+		})
+	}
+	
+	fn entry(size: u64) -> Entry {
+		let source = Source {
+			id: "source".into(),
+			is_file: false,
+			is_stdin: false,
+			path: PathBuf::from("/source").into(),
+		};
+		
+		Entry {
+			source,
+			path: PathBuf::from("/source/file"),
+			size,
+			modified: SystemTime::UNIX_EPOCH,
+			part: None,
+		}
+	}
+	
+	fn offset_and_size(entry: &Entry) -> (u64, u64) {
+		(entry.part.expect("entry should be a part").offset, entry.size)
+	}
+	
+	#[test]
+	fn records_round_trip() {
+		let parts = [
+			part(0, Some(10)),
+			None,
+			part(5, None),
+			part(10u64.pow(18), Some(u64::MAX)),
+			part(1234567, None),
+		];
+		
+		let mut builder = tar::Builder::new(Vec::new());
+		
+		for (i, part) in parts.iter().enumerate() {
+			if let Some(part) = part {
+				part.write_record(&mut builder).unwrap();
+			}
+			
+			let mut header = tar::Header::new_gnu();
+			header.set_size(1);
+			header.set_mode(0o644);
+			builder.append_data(&mut header, format!("file{i}"), &b"x"[..]).unwrap();
+		}
+		
+		let data = builder.into_inner().unwrap();
+		let mut archive = tar::Archive::new(&data[..]);
+		let read: Vec<Option<(u64, Option<u64>)>> = archive.entries().unwrap()
+			.map(|entry| Part::read(&mut entry.unwrap()).unwrap())
+			.map(|part| part.map(|part| (part.offset, part.total_size)))
+			.collect();
+		
+		let expected: Vec<Option<(u64, Option<u64>)>> = parts.iter()
+			.map(|part| part.map(|part| (part.offset, part.total_size)))
+			.collect();
+		assert_eq!(read, expected);
+	}
+	
+	#[test]
+	fn split_entries_join_again() {
+		let parts = split_entry(entry(25), 10);
+		let ranges: Vec<(u64, u64)> = parts.iter().map(offset_and_size).collect();
+		assert_eq!(ranges, [(0, 10), (10, 10), (20, 5)]);
+		assert!(parts.iter().all(|part| part.part.unwrap().total_size == Some(25)));
+		
+		let [_, middle, _] = <[Entry; 3]>::try_from(parts).unwrap();
+		let (front, back) = split_front(middle, 4);
+		assert_eq!(offset_and_size(&front), (10, 4));
+		assert_eq!(offset_and_size(&back), (14, 6));
+		
+		let joined = join_front(front, back);
+		assert_eq!(offset_and_size(&joined), (10, 10));
+	}
+	
+	#[test]
+	fn whole_files_are_no_part_once_joined() {
+		let (front, back) = split_front(entry(25), 7);
+		assert_eq!(offset_and_size(&front), (0, 7));
+		assert_eq!(offset_and_size(&back), (7, 18));
+		assert!(join_front(front, back).part.is_none());
+	}
+}
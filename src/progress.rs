@@ -33,7 +33,7 @@ impl ProgressDisplay {
 		}
 	}
 	
-	pub fn new_tracker(&self, label: impl Into<Cow<'static, str>>, total_progress: u64) -> ProgressTracker {
+	pub fn new_tracker(&self, label: impl Into<Cow<'static, str>>, total_progress: u64) -> ProgressTracker<'_> {
 		let progress = ProgressBar::new(total_progress)
 			.with_finish(ProgressFinish::AndLeave)
 			.with_style(self.style.clone())
@@ -44,7 +44,6 @@ impl ProgressDisplay {
 		ProgressTracker {
 			display: self,
 			progress,
-			total_progress,
 		}
 	}
 }
@@ -52,7 +51,6 @@ impl ProgressDisplay {
 pub struct ProgressTracker<'a> {
 	display: &'a ProgressDisplay,
 	progress: ProgressBar,
-	total_progress: u64,
 }
 
 impl ProgressTracker<'_> {
@@ -60,9 +58,21 @@ impl ProgressTracker<'_> {
 		self.display.total_progress.inc(amount);
 		self.progress.inc(amount);
 		
-		if self.progress.position() == self.total_progress {
+		if Some(self.progress.position()) == self.progress.length() {
 			self.progress.set_style(self.display.finished_style.clone());
 			self.progress.finish();
 		}
 	}
+	
+	/// Adds work whose size wasn't known when the tracker was created, such as data read from stdin
+	pub fn extend(&self, amount: u64) {
+		self.display.total_progress.inc_length(amount);
+		self.progress.inc_length(amount);
+	}
+	
+	/// Takes back progress of work which has to be redone
+	pub fn rewind(&self, amount: u64) {
+		self.display.total_progress.dec(amount);
+		self.progress.dec(amount);
+	}
 }
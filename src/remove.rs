@@ -0,0 +1,153 @@
+use std::{fs::{self, File}, io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write}, mem, path::PathBuf, thread};
+
+use crate::{archive::{sub_archive::SubArchive, Archive}, compression::Decoder, crypto::{generate_iv, EncryptWriter, Key}, output::Output, pack::{write_source_groups, SourceGroup}, segment::SegmentWriter, BKY_HEADER, END_MARKER};
+
+/// Removes a source from an archive by rewriting the files containing it without its source group
+///
+/// The other source groups are copied without decompressing them if they are split into segments,
+/// otherwise they are compressed again. The rewritten files are encrypted with a new IV and only replace
+/// the previous ones once all of them are written, files only containing the source are deleted.
+pub fn remove_source(archive: PathBuf, source: &str, key: Key) -> Result<(), io::Error> {
+	let paths = if archive.is_dir() {
+		Archive::new(archive, key).volume_paths()?
+	} else {
+		vec![archive]
+	};
+	
+	// the rewritten files replace the previous ones once all of them are written
+	let output = Output::new(true);
+	let mut is_rewritten = false;
+	let mut emptied = Vec::new();
+	let mut has_other_sources = false;
+	
+	for path in paths {
+		let sub_archive = SubArchive::new(BufReader::new(File::open(&path)?), key)?;
+		
+		if sub_archive.sources().all(|id| id != source) {
+			has_other_sources = true;
+			continue;
+		}
+		
+		if sub_archive.sources().all(|id| id == source) {
+			emptied.push(path);
+			continue;
+		}
+		
+		let file = output.create_file(&path)?;
+		rewrite_without_source(sub_archive, source, &file, key)?;
+		
+		is_rewritten = true;
+		has_other_sources = true;
+	}
+	
+	if !is_rewritten && emptied.is_empty() {
+		return Err(io::Error::new(io::ErrorKind::NotFound, format!("source {source} is not contained in this archive")));
+	}
+	
+	if !has_other_sources {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("source {source} is the only source of this archive")));
+	}
+	
+	output.finish()?;
+	
+	for path in emptied {
+		fs::remove_file(path)?;
+	}
+	
+	Ok(())
+}
+
+/// Writes the sub archive without the source groups of the source
+fn rewrite_without_source<R: Read + Seek>(
+	sub_archive: SubArchive<R>,
+	source: &str,
+	file: &File,
+	key: Key
+) -> Result<(), io::Error> {
+	let compression = sub_archive.compression();
+	let is_segmented = sub_archive.is_segmented();
+	// the new file doesn't contain padding, stores its header at the start unless the groups are compressed again
+	// and ends with the end marker
+	let mut flags = sub_archive.flags() & 1 | 2 | 16;
+	
+	if !is_segmented {
+		flags |= 8;
+	}
+	
+	let (mut decrypter, source_groups) = sub_archive.into_parts();
+	
+	let mut writer = BufWriter::new(file);
+	let iv = generate_iv();
+	writer.write_all(BKY_HEADER)?;
+	writer.write_all(&iv)?;
+	let mut encrypter = EncryptWriter::new(&mut writer, key, iv);
+	
+	encrypter.write_all(&flags.to_le_bytes())?;
+	encrypter.write_all(&[compression.to_byte()])?;
+	
+	let mut encrypter = if is_segmented {
+		let kept_groups: Vec<SourceGroup> = source_groups.iter()
+			.filter(|group| group.id != source)
+			.map(|group| SourceGroup::from(group.clone()))
+			.collect();
+		write_source_groups(&mut encrypter, &kept_groups)?;
+		
+		// the compressed data of the segments is only decrypted and encrypted again
+		for group in source_groups {
+			group.seek_to_data(&mut decrypter, key)?;
+			let compressed_size: u64 = group.segments.iter()
+				.map(|segment| segment.compressed_size)
+				.sum();
+			
+			if group.id == source {
+				decrypter.seek(SeekFrom::Current(compressed_size as i64))?;
+			} else if io::copy(&mut (&mut decrypter).take(compressed_size), &mut encrypter)? < compressed_size {
+				return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "segments of the archive are incomplete"));
+			}
+		}
+		
+		encrypter
+	} else {
+		// all source groups share one compressed stream, so the kept ones are compressed again into a segment each
+		let threads = thread::available_parallelism()
+			.map(|threads| threads.get() as u32)
+			.unwrap_or(1);
+		
+		let mut decoder = Decoder::new(decrypter, compression)?;
+		let mut kept_groups = Vec::new();
+		
+		for group in source_groups {
+			let mut tar = (&mut decoder).take(group.size);
+			
+			if group.id == source {
+				io::copy(&mut tar, &mut io::sink())?;
+				continue;
+			}
+			
+			let mut segment_writer = SegmentWriter::new(encrypter, compression, compression.default_level(), threads, true)?;
+			io::copy(&mut tar, &mut segment_writer)?;
+			let (inner, segments) = segment_writer.finish()?;
+			encrypter = inner;
+			
+			let mut kept_group = SourceGroup::from(group);
+			kept_group.segments = segments;
+			kept_groups.push(kept_group);
+		}
+		
+		let mut trailer = Vec::new();
+		write_source_groups(&mut trailer, &kept_groups)?;
+		let trailer_len: u64 = trailer.len() as u64;
+		
+		encrypter.write_all(&trailer)?;
+		encrypter.write_all(&trailer_len.to_le_bytes())?;
+		
+		encrypter
+	};
+	
+	encrypter.write_all(END_MARKER)?;
+	encrypter.flush()?;
+	mem::drop(encrypter);
+	
+	writer.flush()?;
+	file.sync_all()
+}
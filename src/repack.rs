@@ -0,0 +1,540 @@
+use std::{borrow::Cow, fs::File, io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write}, mem, path::{Component, Path, PathBuf}, thread};
+
+use crate::{archive::{sub_archive::SubArchive, Archive}, compression::{has_compressed_extension, Compression, Decoder}, crypto::{generate_iv, EncryptWriter, Key}, output::Output, pack::{encoder_threads, write_flags, write_source_groups, PackOptions, SourceGroup}, part::Part, progress::{ProgressDisplay, ProgressTracker}, segment::{SegmentReader, SegmentWriter}, Source, BKY_HEADER, END_MARKER};
+
+type VolumeWriter = EncryptWriter<BufWriter<File>>;
+
+/// Packs the contents of an existing archive into a new archive at `out`, split into files like `pack` if `max_group_size` is set
+///
+/// This re-splits an archive into files of a different size or merges a split archive into a single file.
+/// The files are only decrypted and decompressed in memory while they are packed again, and files larger than
+/// the new file size are split into parts. The headers of the new files are stored at their end.
+pub fn repack(archive: PathBuf, out: PathBuf, key: Key, options: PackOptions) -> Result<(), io::Error> {
+	if options.max_group_size == Some(0) {
+		panic!("max_group_size must be greater than 0");
+	}
+	
+	if options.limit_output_size || !options.incremental_from.is_empty() || options.stdin_name.is_some() || !options.named_sources.is_empty() || options.deterministic_iv {
+		panic!("limit_output_size, incremental_from, stdin_name, named_sources and deterministic_iv can't be used when repacking");
+	}
+	
+	let paths = if archive.is_dir() {
+		Archive::new(archive, key).volume_paths()?
+	} else {
+		vec![archive]
+	};
+	
+	// the source groups of each source are packed together, with all deletions and warnings in the first one
+	let mut sources: Vec<SourceGroup> = Vec::new();
+	let mut is_single_source = false;
+	let mut total_size = 0;
+	
+	for (i, path) in paths.iter().enumerate() {
+		let sub_archive = SubArchive::new(BufReader::new(File::open(path)?), key)?;
+		
+		if i == 0 {
+			is_single_source = sub_archive.is_single_source();
+		}
+		
+		for group in sub_archive.into_source_groups() {
+			total_size += group.size;
+			
+			let mut group = SourceGroup::from(group);
+			group.segments.clear();
+			
+			match sources.iter_mut().find(|source| source.source.id == group.source.id) {
+				Some(source) => {
+					source.deletions.append(&mut group.deletions);
+					source.warnings.append(&mut group.warnings);
+				},
+				None => sources.push(group),
+			}
+		}
+	}
+	
+	let available_threads = thread::available_parallelism()
+		.map(|threads| threads.get() as u32)
+		.unwrap_or(1);
+	
+	let progress_display = ProgressDisplay::new(total_size);
+	let progress_tracker = progress_display.new_tracker("Total", total_size);
+	
+	let mut repacker = Repacker::new(
+		&out,
+		key,
+		&options,
+		encoder_threads(options.threads.unwrap_or(available_threads), &options),
+		is_single_source
+	)?;
+	
+	for source in &mut sources {
+		repacker.start_source(source)?;
+		
+		for path in &paths {
+			repack_source_groups(&mut repacker, path, &source.source.id, key, &progress_tracker)?;
+		}
+	}
+	
+	repacker.finish()
+}
+
+/// Packs the contents of a tar stream into a new archive at `out`, split into files like `pack` if `max_group_size` is set
+///
+/// Each top level directory or file of the tar stream becomes a source, like the sources written by `export`.
+pub fn import_tar(reader: impl Read, out: PathBuf, key: Key, options: PackOptions) -> Result<(), io::Error> {
+	if options.max_group_size == Some(0) {
+		panic!("max_group_size must be greater than 0");
+	}
+	
+	if options.limit_output_size || !options.incremental_from.is_empty() || options.stdin_name.is_some() || !options.named_sources.is_empty() || options.deterministic_iv {
+		panic!("limit_output_size, incremental_from, stdin_name, named_sources and deterministic_iv can't be used when importing");
+	}
+	
+	let available_threads = thread::available_parallelism()
+		.map(|threads| threads.get() as u32)
+		.unwrap_or(1);
+	
+	let mut repacker = Repacker::new(
+		&out,
+		key,
+		&options,
+		encoder_threads(options.threads.unwrap_or(available_threads), &options),
+		false
+	)?;
+	
+	let mut archive = tar::Archive::new(reader);
+	let mut current_source: Option<Source> = None;
+	
+	for entry in archive.entries()? {
+		let entry = entry?;
+		let entry_type = entry.header().entry_type();
+		
+		if entry_type == tar::EntryType::XGlobalHeader {
+			continue;
+		}
+		
+		let path = entry.path()?.into_owned();
+		
+		let Some((id, relative_path)) = split_source(&path)? else {
+			continue;
+		};
+		
+		// the entries of top level directories are created when unpacking their sources
+		if relative_path.as_os_str().is_empty() && entry_type.is_dir() {
+			continue;
+		}
+		
+		let is_file = relative_path.as_os_str().is_empty();
+		
+		if current_source.as_ref().is_none_or(|source| *source.id != *id || source.is_file != is_file) {
+			let source = Source {
+				id: id.as_str().into(),
+				is_file,
+				is_stdin: false,
+				path: Path::new(&id).into(),
+			};
+			
+			repacker.start_source(&mut SourceGroup::new(source.clone()))?;
+			current_source = Some(source);
+		}
+		
+		let path = if is_file {
+			PathBuf::from(id)
+		} else {
+			relative_path
+		};
+		
+		// hard links point to other entries of the tar stream, which are now located below the source
+		let link_name = match entry.link_name()? {
+			Some(link_name) if entry_type.is_hard_link() && !is_file => {
+				let (_, link_path) = split_source(&link_name)?
+					.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("hard link {} points to the top level directory", path.display())))?;
+				Some(link_path)
+			},
+			link_name => link_name.map(Cow::into_owned),
+		};
+		
+		repacker.append_entry(entry, &path, link_name.as_deref(), None)?;
+	}
+	
+	repacker.finish()
+}
+
+/// Splits a path of a tar stream into the name of its source and the path below the source,
+/// returns `None` for the current directory, like the `./` entry of `tar -C dir -cf x.tar .`
+fn split_source(path: &Path) -> Result<Option<(String, PathBuf)>, io::Error> {
+	let mut components = path.components()
+		.filter(|component| *component != Component::CurDir);
+	
+	let id = match components.next() {
+		Some(Component::Normal(id)) => id,
+		None => return Ok(None),
+		Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a relative path", path.display()))),
+	};
+	
+	Ok(Some((id.to_string_lossy().into_owned(), components.collect())))
+}
+
+/// Packs the source groups of the source contained in the file at `path` again
+fn repack_source_groups(
+	repacker: &mut Repacker,
+	path: &Path,
+	source: &str,
+	key: Key,
+	progress_tracker: &ProgressTracker
+) -> Result<(), io::Error> {
+	let sub_archive = SubArchive::new(BufReader::new(File::open(path)?), key)?;
+	
+	if sub_archive.sources().all(|id| id != source) {
+		return Ok(());
+	}
+	
+	let compression = sub_archive.compression();
+	let is_segmented = sub_archive.is_segmented();
+	let (mut decrypter, source_groups) = sub_archive.into_parts();
+	
+	if is_segmented {
+		for group in &source_groups {
+			group.seek_to_data(&mut decrypter, key)?;
+			
+			if group.id != source {
+				let compressed_size: u64 = group.segments.iter()
+					.map(|segment| segment.compressed_size)
+					.sum();
+				decrypter.seek(SeekFrom::Current(compressed_size as i64))?;
+				continue;
+			}
+			
+			repacker.append_tar(SegmentReader::new(&mut decrypter, &group.segments))?;
+			progress_tracker.advance(group.size);
+		}
+	} else {
+		// all source groups share one compressed stream
+		let mut decoder = Decoder::new(decrypter, compression)?;
+		
+		for group in &source_groups {
+			let mut tar = (&mut decoder).take(group.size);
+			
+			if group.id != source {
+				io::copy(&mut tar, &mut io::sink())?;
+				continue;
+			}
+			
+			repacker.append_tar(tar)?;
+			progress_tracker.advance(group.size);
+		}
+	}
+	
+	Ok(())
+}
+
+/// Writes the entries of the existing archive into the files of the new archive one after another
+struct Repacker<'a> {
+	output: Output,
+	out: &'a Path,
+	key: Key,
+	options: &'a PackOptions,
+	threads: u32,
+	is_single_source: bool,
+	/// Number of the current file if the new archive is split
+	number: u64,
+	volume: Volume,
+}
+
+impl<'a> Repacker<'a> {
+	fn new(out: &'a Path, key: Key, options: &'a PackOptions, threads: u32, is_single_source: bool) -> Result<Self, io::Error> {
+		let output = Output::new(options.overwrite);
+		
+		if options.max_group_size.is_some() {
+			output.create_volume_dir(out)?;
+		}
+		
+		let path = volume_path(out, options, 1);
+		let volume = Volume::create(&output, &path, key, options, is_single_source)?;
+		
+		Ok(Self {
+			output,
+			out,
+			key,
+			options,
+			threads,
+			is_single_source,
+			number: 1,
+			volume,
+		})
+	}
+	
+	/// Starts a new source group for the source, taking its deletions and warnings
+	fn start_source(&mut self, source: &mut SourceGroup) -> Result<(), io::Error> {
+		self.volume.finish_group(self.options, self.threads)?;
+		
+		let mut group = SourceGroup::new(source.source.clone());
+		group.deletions = mem::take(&mut source.deletions);
+		group.warnings = mem::take(&mut source.warnings);
+		self.volume.source_groups.push(group);
+		
+		Ok(())
+	}
+	
+	/// Finishes the current file and continues the current source group in a new one
+	fn next_volume(&mut self) -> Result<(), io::Error> {
+		self.number += 1;
+		let path = volume_path(self.out, self.options, self.number);
+		let volume = Volume::create(&self.output, &path, self.key, self.options, self.is_single_source)?;
+		
+		let mut previous = mem::replace(&mut self.volume, volume);
+		
+		if let Some(group) = previous.source_groups.last() {
+			self.volume.source_groups.push(SourceGroup::new(group.source.clone()));
+		}
+		
+		previous.finish_group(self.options, self.threads)?;
+		previous.finish()
+	}
+	
+	fn append_tar(&mut self, reader: impl Read) -> Result<(), io::Error> {
+		let mut archive = tar::Archive::new(reader);
+		
+		for entry in archive.entries()? {
+			let mut entry = entry?;
+			let part = Part::read(&mut entry)?;
+			let path = entry.path()?.into_owned();
+			let link_name = entry.link_name()?.map(Cow::into_owned);
+			
+			self.append_entry(entry, &path, link_name.as_deref(), part)?;
+		}
+		
+		// the tar archive might be followed by data which isn't used
+		io::copy(&mut archive.into_inner(), &mut io::sink())?;
+		
+		Ok(())
+	}
+	
+	/// Writes an entry at `path`, splitting it into parts if it is larger than a file of the new archive
+	fn append_entry(
+		&mut self,
+		mut entry: tar::Entry<impl Read>,
+		path: &Path,
+		link_name: Option<&Path>,
+		part: Option<Part>
+	) -> Result<(), io::Error> {
+		let mut header = entry.header().clone();
+		let size = entry.size();
+		
+		let Some(max_volume_size) = self.options.max_group_size else {
+			return self.write_entry(&mut header, path, link_name, part, entry);
+		};
+		
+		if self.volume.size > 0 && self.volume.size + size > max_volume_size {
+			self.next_volume()?;
+		}
+		
+		// parts which fill a whole file are packed into files of their own, like when packing
+		let first_part = part.unwrap_or(Part {
+			offset: 0,
+			total_size: Some(size),
+		});
+		let mut offset = 0;
+		
+		while size - offset > max_volume_size {
+			let part = Part {
+				offset: first_part.offset + offset,
+				total_size: first_part.total_size,
+			};
+			
+			header.set_size(max_volume_size);
+			self.write_entry(&mut header, path, None, Some(part), (&mut entry).take(max_volume_size))?;
+			self.next_volume()?;
+			
+			offset += max_volume_size;
+		}
+		
+		let part = if offset > 0 {
+			Some(Part {
+				offset: first_part.offset + offset,
+				total_size: first_part.total_size,
+			})
+		} else {
+			part
+		};
+		
+		header.set_size(size - offset);
+		self.write_entry(&mut header, path, link_name, part, entry)
+	}
+	
+	fn write_entry(
+		&mut self,
+		header: &mut tar::Header,
+		path: &Path,
+		link_name: Option<&Path>,
+		part: Option<Part>,
+		data: impl Read
+	) -> Result<(), io::Error> {
+		let size = header.entry_size()?;
+		let is_compressed = !has_compressed_extension(path, &self.options.store_extensions);
+		let tar_builder = self.volume.tar_builder(is_compressed, self.options, self.threads)?;
+		
+		if let Some(part) = part {
+			part.write_record(tar_builder)?;
+		}
+		
+		match link_name {
+			Some(link_name) => tar_builder.append_link(header, path, link_name)?,
+			None => tar_builder.append_data(header, path, data)?,
+		}
+		
+		self.volume.size += size;
+		
+		Ok(())
+	}
+	
+	fn finish(mut self) -> Result<(), io::Error> {
+		self.volume.finish_group(self.options, self.threads)?;
+		self.volume.finish()?;
+		self.output.finish()?;
+		
+		Ok(())
+	}
+}
+
+/// File of the new archive which is currently written
+struct Volume {
+	/// Only taken while a source group is written to the tar builder
+	encrypter: Option<VolumeWriter>,
+	tar_builder: Option<tar::Builder<SegmentWriter<VolumeWriter>>>,
+	source_groups: Vec<SourceGroup>,
+	/// Size of the files packed into this file so far
+	size: u64,
+}
+
+impl Volume {
+	fn create(output: &Output, path: &Path, key: Key, options: &PackOptions, is_single_source: bool) -> Result<Self, io::Error> {
+		let mut writer = BufWriter::new(output.create_file(path)?);
+		
+		let iv = generate_iv();
+		writer.write_all(BKY_HEADER)?;
+		writer.write_all(&iv)?;
+		let mut encrypter = EncryptWriter::new(writer, key, iv);
+		
+		// the sizes of the source groups are only known once they are written, so the header is stored at the end
+		write_flags(&mut encrypter, options, is_single_source, 8)?;
+		
+		Ok(Self {
+			encrypter: Some(encrypter),
+			tar_builder: None,
+			source_groups: Vec::new(),
+			size: 0,
+		})
+	}
+	
+	/// Tar builder of the current source group, writing to a compressed or stored segment
+	fn tar_builder(
+		&mut self,
+		is_compressed: bool,
+		options: &PackOptions,
+		threads: u32
+	) -> Result<&mut tar::Builder<SegmentWriter<VolumeWriter>>, io::Error> {
+		if let Some(tar_builder) = &mut self.tar_builder {
+			let segment_writer = tar_builder.get_mut();
+			
+			if options.compression != Compression::None && (segment_writer.compression() != Compression::None) != is_compressed {
+				segment_writer.start_segment(is_compressed)?;
+			}
+		} else {
+			let encrypter = self.encrypter.take().expect("encrypter should be available between source groups");
+			let segment_writer = SegmentWriter::new(
+				encrypter,
+				options.compression,
+				options.compression_level,
+				threads,
+				is_compressed
+			)?;
+			self.tar_builder = Some(tar::Builder::new(segment_writer));
+		}
+		
+		Ok(self.tar_builder.as_mut().expect("tar builder should have been created"))
+	}
+	
+	fn finish_group(&mut self, options: &PackOptions, threads: u32) -> Result<(), io::Error> {
+		if self.source_groups.is_empty() {
+			return Ok(());
+		}
+		
+		// groups without entries still contain the end of the tar archive
+		self.tar_builder(true, options, threads)?;
+		
+		let tar_builder = self.tar_builder.take().expect("tar builder should have been created");
+		let (encrypter, segments) = tar_builder.into_inner()?.finish()?;
+		self.encrypter = Some(encrypter);
+		
+		self.source_groups.last_mut().expect("source groups should not be empty").segments = segments;
+		
+		Ok(())
+	}
+	
+	fn finish(mut self) -> Result<(), io::Error> {
+		let mut encrypter = self.encrypter.take().expect("source group should be finished");
+		
+		let mut trailer = Vec::new();
+		write_source_groups(&mut trailer, &self.source_groups)?;
+		let trailer_len: u64 = trailer.len() as u64;
+		
+		encrypter.write_all(&trailer)?;
+		encrypter.write_all(&trailer_len.to_le_bytes())?;
+		encrypter.write_all(END_MARKER)?;
+		encrypter.flush()?;
+		
+		encrypter.into_inner()
+			.into_inner()
+			.map_err(io::IntoInnerError::into_error)?
+			.sync_all()
+	}
+}
+
+fn volume_path(out: &Path, options: &PackOptions, number: u64) -> PathBuf {
+	if options.max_group_size.is_some() {
+		out.join(format!("{number}.bky"))
+	} else {
+		out.to_owned()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	
+	use crate::crypto::generate_key;
+	
+	use super::*;
+	
+	#[test]
+	fn imports_tar_with_current_directory_prefix() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut builder = tar::Builder::new(Vec::new());
+		
+		for path in ["./", "./data/"] {
+			let mut header = tar::Header::new_gnu();
+			header.set_entry_type(tar::EntryType::Directory);
+			header.set_mode(0o755);
+			header.set_size(0);
+			builder.append_data(&mut header, path, io::empty()).unwrap();
+		}
+		
+		let mut header = tar::Header::new_gnu();
+		header.set_mode(0o644);
+		header.set_size(5);
+		builder.append_data(&mut header, "./data/file", &b"hello"[..]).unwrap();
+		let tar = builder.into_inner().unwrap();
+		
+		let key = generate_key();
+		let out = dir.path().join("out.bky");
+		import_tar(&tar[..], out.clone(), key, PackOptions::default()).unwrap();
+		
+		let archive = Archive::new(out, key);
+		assert_eq!(archive.sources().unwrap(), ["data"]);
+		
+		let unpacked = dir.path().join("unpacked");
+		archive.unpack(unpacked.clone()).unwrap();
+		assert_eq!(fs::read(unpacked.join("data/file")).unwrap(), b"hello");
+	}
+}
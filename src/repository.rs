@@ -0,0 +1,512 @@
+use std::{borrow::Cow, ffi::OsString, fs::{self, File}, io::{self, Read, Write}, os::unix::{ffi::{OsStrExt, OsStringExt}, fs::{MetadataExt, PermissionsExt}}, path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use fastcdc::v2020::StreamCDC;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{compression::{Compression, Decoder, Encoder}, crypto::{generate_iv, DecryptReader, EncryptWriter, Key, IV}, index::{create_index, index_files}, pack::{create_filter, create_sources, is_stored, read_files_from, PackOptions}, progress::ProgressDisplay};
+
+const REPOSITORY_HEADER: &[u8] = b"backy repository v1\n";
+const SNAPSHOT_HEADER: &[u8] = b"backy snapshot v1\n";
+
+const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+type ChunkId = [u8; blake3::OUT_LEN];
+
+/// Deduplicating store of encrypted chunks, referenced by snapshots
+pub struct Repository {
+	path: PathBuf,
+	key: Key,
+	/// Key used to derive chunk ids from their contents, so the ids don't reveal the contents
+	id_key: [u8; blake3::KEY_LEN],
+}
+
+pub struct SnapshotInfo {
+	pub id: String,
+	pub time: SystemTime,
+	pub sources: Vec<String>,
+}
+
+struct SnapshotSource {
+	id: String,
+	is_file: bool,
+}
+
+struct SnapshotFile {
+	source: u32,
+	path: PathBuf,
+	size: u64,
+	mode: u32,
+	mtime: i64,
+	chunks: Vec<ChunkId>,
+}
+
+pub struct Snapshot<'a> {
+	repository: &'a Repository,
+	time: SystemTime,
+	is_single_source: bool,
+	sources: Vec<SnapshotSource>,
+	files: Vec<SnapshotFile>,
+}
+
+impl Repository {
+	pub fn new(path: PathBuf, key: Key) -> Self {
+		// TODO: return custom errors
+		if !path.join("repository").exists() {
+			panic!("Repository doesn't exist");
+		}
+		
+		Self::with_path(path, key)
+	}
+	
+	/// Opens the repository at `path`, creating it if it doesn't exist yet
+	pub fn open_or_create(path: PathBuf, key: Key) -> Result<Self, io::Error> {
+		if !path.join("repository").exists() {
+			fs::create_dir_all(path.join("chunks"))?;
+			fs::create_dir_all(path.join("snapshots"))?;
+			fs::write(path.join("repository"), REPOSITORY_HEADER)?;
+		}
+		
+		Ok(Self::with_path(path, key))
+	}
+	
+	fn with_path(path: PathBuf, key: Key) -> Self {
+		let id_key = blake3::derive_key("backy repository chunk id", &key);
+		
+		Self {
+			path,
+			key,
+			id_key,
+		}
+	}
+	
+	/// Stores the given sources as a new snapshot and returns its id
+	pub fn create_snapshot(&self, sources: Vec<PathBuf>, options: &PackOptions) -> Result<String, io::Error> {
+		if options.continue_on_error {
+			panic!("continue_on_error can't be used with repositories, as snapshots can't store warnings");
+		}
+		
+		let files = read_files_from(options)?;
+		let sources = create_sources(sources, files.as_deref(), options);
+		let is_single_source = sources.len() == 1;
+		
+		let snapshot_sources: Vec<SnapshotSource> = sources.iter()
+			.map(|source| SnapshotSource {
+				id: source.id.to_string(),
+				is_file: source.is_file,
+			})
+			.collect();
+		
+		let (index, _, total_size) = match files {
+			Some(files) => index_files(&sources, files, false)?,
+			None => create_index(sources, &create_filter(options)?, false)?,
+		};
+		
+		let progress_display = ProgressDisplay::new(total_size);
+		let progress_tracker = progress_display.new_tracker("Total", total_size);
+		
+		let new_chunks = AtomicU64::new(0);
+		let new_chunks_size = AtomicU64::new(0);
+		
+		let files = index.par_iter()
+			.map(|entry| -> Result<_, io::Error> {
+				let stored = is_stored(entry, options);
+				let compression = if stored {
+					Compression::None
+				} else {
+					options.compression
+				};
+				
+				let mut retries = options.changed_retries;
+				
+				// the chunks are already stored while the file is read, so a file which changed is simply read again
+				let (metadata, size, chunks) = loop {
+					let file = File::open(&entry.path)?;
+					let metadata = file.metadata()?;
+					let mut chunks = Vec::new();
+					let mut size = 0;
+					
+					for chunk in StreamCDC::new(&file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+						let chunk = chunk?;
+						let id = *blake3::keyed_hash(&self.id_key, &chunk.data).as_bytes();
+						
+						if self.store_chunk(&id, &chunk.data, compression, options.compression_level)? {
+							new_chunks.fetch_add(1, Ordering::Relaxed);
+							new_chunks_size.fetch_add(chunk.length as u64, Ordering::Relaxed);
+						}
+						
+						chunks.push(id);
+						size += chunk.length as u64;
+						progress_tracker.advance(chunk.length as u64);
+					}
+					
+					let read_metadata = file.metadata()?;
+					
+					if read_metadata.len() == metadata.len() && read_metadata.modified().ok() == metadata.modified().ok() {
+						break (metadata, size, chunks);
+					}
+					
+					// snapshots can't store warnings, so the file is only reported
+					if retries == 0 {
+						eprintln!("{} changed while it was read.", entry.path.to_string_lossy());
+						break (metadata, size, chunks);
+					}
+					
+					eprintln!("{} changed while it was read, reading it again.", entry.path.to_string_lossy());
+					progress_tracker.rewind(size);
+					retries -= 1;
+				};
+				
+				Ok(SnapshotFile {
+					source: snapshot_sources.iter()
+						.position(|source| *source.id == *entry.source.id)
+						.expect("all entries should belong to a source") as u32,
+					path: entry.relative_path().to_owned(),
+					size,
+					mode: metadata.mode(),
+					mtime: metadata.mtime(),
+					chunks,
+				})
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+		
+		let format = humansize::make_format(humansize::BINARY);
+		println!(
+			"Stored {} new chunks with a total size of {}.",
+			new_chunks.load(Ordering::Relaxed),
+			format(new_chunks_size.load(Ordering::Relaxed)),
+		);
+		
+		let snapshot = Snapshot {
+			repository: self,
+			time: SystemTime::now(),
+			is_single_source,
+			sources: snapshot_sources,
+			files,
+		};
+		
+		let mut id_bytes = [0u8; 8];
+		getrandom::fill(&mut id_bytes).expect("random data should be available");
+		let id = to_hex(&id_bytes);
+		
+		let mut data = Vec::new();
+		snapshot.write(&mut data)?;
+		
+		let mut contents = SNAPSHOT_HEADER.to_vec();
+		contents.append(&mut encrypt(self.key, &data, options.compression, options.compression_level)?);
+		write_new(&self.path.join("snapshots").join(&id), &contents)?;
+		
+		Ok(id)
+	}
+	
+	pub fn snapshots(&self) -> Result<Vec<SnapshotInfo>, io::Error> {
+		let mut snapshots = Vec::new();
+		
+		for entry in fs::read_dir(self.path.join("snapshots"))? {
+			let id = entry?.file_name().to_string_lossy().into_owned();
+			
+			// left over from an interrupted write
+			if id.ends_with(".tmp") {
+				continue;
+			}
+			
+			let snapshot = self.snapshot(&id)?;
+			
+			snapshots.push(SnapshotInfo {
+				time: snapshot.time,
+				sources: snapshot.sources(),
+				id,
+			});
+		}
+		
+		snapshots.sort_by_key(|snapshot| snapshot.time);
+		
+		Ok(snapshots)
+	}
+	
+	pub fn snapshot(&self, id: &str) -> Result<Snapshot<'_>, io::Error> {
+		let path = self.path.join("snapshots").join(id);
+		
+		if !path.exists() {
+			panic!("Snapshot {id} doesn't exist");
+		}
+		
+		let contents = fs::read(path)?;
+		
+		if !contents.starts_with(SNAPSHOT_HEADER) {
+			panic!("Not a backy snapshot");
+		}
+		
+		let data = decrypt(self.key, &contents[SNAPSHOT_HEADER.len()..])?;
+		Snapshot::read(self, &mut &data[..])
+	}
+	
+	fn chunk_path(&self, id: &ChunkId) -> PathBuf {
+		let hex = to_hex(id);
+		self.path.join("chunks").join(&hex[..2]).join(hex)
+	}
+	
+	/// Stores a chunk unless it already exists, returns whether it was newly stored
+	fn store_chunk(&self, id: &ChunkId, data: &[u8], compression: Compression, compression_level: u32) -> Result<bool, io::Error> {
+		let path = self.chunk_path(id);
+		
+		if path.exists() {
+			return Ok(false);
+		}
+		
+		fs::create_dir_all(path.parent().expect("chunk path should have a parent"))?;
+		write_new(&path, &encrypt(self.key, data, compression, compression_level)?)?;
+		
+		Ok(true)
+	}
+	
+	fn read_chunk(&self, id: &ChunkId) -> Result<Vec<u8>, io::Error> {
+		let contents = fs::read(self.chunk_path(id))?;
+		decrypt(self.key, &contents)
+	}
+}
+
+impl Snapshot<'_> {
+	pub fn sources(&self) -> Vec<String> {
+		self.sources.iter()
+			.map(|source| source.id.clone())
+			.collect()
+	}
+	
+	pub fn unpack(&self, out: PathBuf) -> Result<(), io::Error> {
+		let total_size = self.files.iter()
+			.map(|file| file.size)
+			.sum();
+		
+		let progress_display = ProgressDisplay::new(total_size);
+		let progress_tracker = progress_display.new_tracker("Total", total_size);
+		
+		self.files.par_iter()
+			.map(|file| -> Result<_, io::Error> {
+				let source = &self.sources[file.source as usize];
+				
+				let directory = if self.is_single_source || source.is_file {
+					Cow::Borrowed(&out)
+				} else {
+					Cow::Owned(out.join(&source.id))
+				};
+				
+				let path = directory.join(&file.path);
+				fs::create_dir_all(path.parent().expect("file path should have a parent"))?;
+				
+				let mut writer = File::create(&path)?;
+				
+				for chunk in &file.chunks {
+					let data = self.repository.read_chunk(chunk)?;
+					writer.write_all(&data)?;
+					progress_tracker.advance(data.len() as u64);
+				}
+				
+				writer.set_permissions(fs::Permissions::from_mode(file.mode))?;
+				writer.set_modified(UNIX_EPOCH + Duration::from_secs(file.mtime.max(0) as u64))?;
+				
+				Ok(())
+			})
+			.collect::<Result<(), _>>()
+	}
+	
+	pub fn for_each_file(&self, mut callback: impl FnMut(&str, &Path)) {
+		for file in &self.files {
+			callback(&self.sources[file.source as usize].id, &file.path);
+		}
+	}
+	
+	pub fn get_file(&self, source: Option<&str>, path: &str, mut writer: impl Write) -> Result<(), io::Error> {
+		let file = self.files.iter()
+			.filter(|file| source.is_none_or(|source| self.sources[file.source as usize].id == source))
+			.find(|file| file.path.to_str() == Some(path));
+		
+		let Some(file) = file else {
+			return Ok(());
+		};
+		
+		for chunk in &file.chunks {
+			writer.write_all(&self.repository.read_chunk(chunk)?)?;
+		}
+		
+		Ok(())
+	}
+	
+	fn write(&self, writer: &mut impl Write) -> Result<(), io::Error> {
+		let time = self.time.duration_since(UNIX_EPOCH)
+			.expect("current time should be after the unix epoch")
+			.as_secs();
+		writer.write_all(&time.to_le_bytes())?;
+		
+		let mut flags = 0u32;
+		
+		if self.is_single_source {
+			flags |= 1;
+		}
+		
+		writer.write_all(&flags.to_le_bytes())?;
+		
+		writer.write_all(&(self.sources.len() as u32).to_le_bytes())?;
+		
+		for source in &self.sources {
+			write_bytes(writer, source.id.as_bytes())?;
+			
+			let mut flags = 0u32;
+			
+			if source.is_file {
+				flags |= 1;
+			}
+			
+			writer.write_all(&flags.to_le_bytes())?;
+		}
+		
+		writer.write_all(&(self.files.len() as u32).to_le_bytes())?;
+		
+		for file in &self.files {
+			writer.write_all(&file.source.to_le_bytes())?;
+			write_bytes(writer, file.path.as_os_str().as_bytes())?;
+			writer.write_all(&file.size.to_le_bytes())?;
+			writer.write_all(&file.mode.to_le_bytes())?;
+			writer.write_all(&file.mtime.to_le_bytes())?;
+			writer.write_all(&(file.chunks.len() as u32).to_le_bytes())?;
+			
+			for chunk in &file.chunks {
+				writer.write_all(chunk)?;
+			}
+		}
+		
+		Ok(())
+	}
+	
+	fn read<'a>(repository: &'a Repository, reader: &mut impl Read) -> Result<Snapshot<'a>, io::Error> {
+		let time = UNIX_EPOCH + Duration::from_secs(read_u64(reader)?);
+		let flags = read_u32(reader)?;
+		let is_single_source = flags & 1 != 0;
+		
+		let sources_len = read_u32(reader)?;
+		let mut sources = Vec::with_capacity(sources_len as usize);
+		
+		for _ in 0..sources_len {
+			let id = String::from_utf8(read_bytes(reader)?)
+				.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+			let flags = read_u32(reader)?;
+			
+			sources.push(SnapshotSource {
+				id,
+				is_file: flags & 1 != 0,
+			});
+		}
+		
+		let files_len = read_u32(reader)?;
+		let mut files = Vec::with_capacity(files_len as usize);
+		
+		for _ in 0..files_len {
+			let source = read_u32(reader)?;
+			let path = PathBuf::from(OsString::from_vec(read_bytes(reader)?));
+			let size = read_u64(reader)?;
+			let mode = read_u32(reader)?;
+			let mtime = read_u64(reader)? as i64;
+			
+			let chunks_len = read_u32(reader)?;
+			let mut chunks = Vec::with_capacity(chunks_len as usize);
+			
+			for _ in 0..chunks_len {
+				let mut chunk = ChunkId::default();
+				reader.read_exact(&mut chunk)?;
+				chunks.push(chunk);
+			}
+			
+			files.push(SnapshotFile {
+				source,
+				path,
+				size,
+				mode,
+				mtime,
+				chunks,
+			});
+		}
+		
+		Ok(Snapshot {
+			repository,
+			time,
+			is_single_source,
+			sources,
+			files,
+		})
+	}
+}
+
+/// Compresses and encrypts data, prefixed by its IV and compression
+fn encrypt(key: Key, data: &[u8], compression: Compression, compression_level: u32) -> Result<Vec<u8>, io::Error> {
+	let iv = generate_iv();
+	let mut contents = iv.to_vec();
+	
+	let mut encrypter = EncryptWriter::new(&mut contents, key, iv);
+	encrypter.write_all(&[compression.to_byte()])?;
+	
+	let mut encoder = Encoder::new(encrypter, compression, compression_level, 0)?;
+	encoder.write_all(data)?;
+	encoder.finish()?;
+	
+	Ok(contents)
+}
+
+fn decrypt(key: Key, contents: &[u8]) -> Result<Vec<u8>, io::Error> {
+	let mut reader = contents;
+	let mut iv = IV::default();
+	reader.read_exact(&mut iv)?;
+	let mut decrypter = DecryptReader::new(reader, key, iv);
+	
+	let mut buf8 = [0u8; size_of::<u8>()];
+	decrypter.read_exact(&mut buf8)?;
+	let compression = Compression::from_byte(buf8[0])
+		.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unknown compression {}", buf8[0])))?;
+	
+	let mut data = Vec::new();
+	Decoder::new(decrypter, compression)?.read_to_end(&mut data)?;
+	
+	Ok(data)
+}
+
+/// Writes a file under a temporary name first, so there are never any partially written files at `path`
+fn write_new(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+	let mut suffix = [0u8; 8];
+	getrandom::fill(&mut suffix).expect("random data should be available");
+	
+	let mut temp_path = path.as_os_str().to_owned();
+	temp_path.push(format!(".{}.tmp", to_hex(&suffix)));
+	
+	fs::write(&temp_path, contents)?;
+	fs::rename(&temp_path, path)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter()
+		.map(|byte| format!("{byte:02x}"))
+		.collect()
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> Result<(), io::Error> {
+	writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+	writer.write_all(bytes)
+}
+
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>, io::Error> {
+	let len = read_u32(reader)?;
+	let mut bytes = vec![0; len as usize];
+	reader.read_exact(&mut bytes)?;
+	Ok(bytes)
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, io::Error> {
+	let mut buf32 = [0u8; size_of::<u32>()];
+	reader.read_exact(&mut buf32)?;
+	Ok(u32::from_le_bytes(buf32))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, io::Error> {
+	let mut buf64 = [0u8; size_of::<u64>()];
+	reader.read_exact(&mut buf64)?;
+	Ok(u64::from_le_bytes(buf64))
+}
@@ -0,0 +1,262 @@
+use std::{fs::{self, File}, io::{self, BufReader, Read}, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, time::UNIX_EPOCH};
+
+use crate::{archive::sub_archive::SubArchive, crypto::Key, group::Group, pack::PackOptions, BKY_HEADER};
+
+/// File in the directory of a resumable split archive, which stores a hash of the contents of each of its files
+const STATE_FILE_NAME: &str = ".backy-resume";
+
+const STATE_HEADER: &[u8] = b"backy resume v1\n";
+
+/// Finds the files of an interrupted pack into `out` which are complete and contain the same data as the groups,
+/// returns for each group whether it is already packed
+///
+/// Files of groups whose contents changed since then, or of groups which don't exist anymore, are left in place
+/// until they are replaced or removed by the new pack, which is only allowed if `overwrite` is set.
+pub(crate) fn resume(out: &Path, groups: &[Group], key: Key, options: &PackOptions, is_single_source: bool) -> Result<Vec<bool>, io::Error> {
+	let state_path = out.join(STATE_FILE_NAME);
+	let previous_hashes = match fs::read(&state_path) {
+		Ok(state) => read_state(&state)?,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+		Err(err) => return Err(err),
+	};
+	
+	let hashes: Vec<blake3::Hash> = groups.iter()
+		.map(|group| hash_group(group, &key, options, is_single_source))
+		.collect();
+	
+	let is_packed = hashes.iter()
+		.enumerate()
+		.map(|(i, hash)| Ok(previous_hashes.get(i) == Some(hash) && is_complete(&volume_path(out, i), key)?))
+		.collect::<Result<Vec<_>, io::Error>>()?;
+	
+	if !options.overwrite {
+		for entry in fs::read_dir(out)? {
+			let path = entry?.path();
+			
+			let index = path.extension()
+				.filter(|&extension| extension == "bky")
+				.and(path.file_stem())
+				.and_then(|stem| stem.to_str()?.parse::<usize>().ok()?.checked_sub(1));
+			
+			// files which don't match the groups are left in place, so they have to be replaced or removed by the new pack
+			if let Some(index) = index
+				&& !is_packed.get(index).is_some_and(|&is_packed| is_packed)
+			{
+				return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+					"{} doesn't match the files to pack anymore, so it can only be replaced with overwrite",
+					path.to_string_lossy()
+				)));
+			}
+		}
+	}
+	
+	let mut state = STATE_HEADER.to_vec();
+	state.extend_from_slice(&(hashes.len() as u64).to_le_bytes());
+	
+	for hash in &hashes {
+		state.extend_from_slice(hash.as_bytes());
+	}
+	
+	let temp_path = out.join(format!("{STATE_FILE_NAME}.tmp"));
+	fs::write(&temp_path, state)?;
+	fs::rename(temp_path, state_path)?;
+	
+	Ok(is_packed)
+}
+
+/// Whether `out` contains the state of an interrupted pack
+pub(crate) fn has_state(out: &Path) -> bool {
+	out.join(STATE_FILE_NAME).is_file()
+}
+
+/// Removes the state once all files of the archive are written
+pub(crate) fn finish(out: &Path) -> Result<(), io::Error> {
+	fs::remove_file(out.join(STATE_FILE_NAME))
+}
+
+fn read_state(state: &[u8]) -> Result<Vec<blake3::Hash>, io::Error> {
+	let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{STATE_FILE_NAME} is not the state of a resumable pack"));
+	
+	let state = state.strip_prefix(STATE_HEADER).ok_or_else(invalid)?;
+	let (len, hashes) = state.split_first_chunk::<{ size_of::<u64>() }>().ok_or_else(invalid)?;
+	let (hashes, rest) = hashes.as_chunks::<{ blake3::OUT_LEN }>();
+	
+	if hashes.len() as u64 != u64::from_le_bytes(*len) || !rest.is_empty() {
+		return Err(invalid());
+	}
+	
+	Ok(hashes.iter().map(|hash| blake3::Hash::from_bytes(*hash)).collect())
+}
+
+/// Hashes everything which determines the contents of the file a group is packed into, keyed so it doesn't reveal the paths
+fn hash_group(group: &Group, key: &Key, options: &PackOptions, is_single_source: bool) -> blake3::Hash {
+	let resume_key = blake3::derive_key("backy resume state", key);
+	let mut hasher = blake3::Hasher::new_keyed(&resume_key);
+	
+	hasher.update(&[is_single_source as u8, options.compression.to_byte(), options.sample_entropy as u8]);
+	hasher.update(&options.compression_level.to_le_bytes());
+	hasher.update(&[options.reproducible as u8, options.deterministic_iv as u8]);
+	hasher.update(&options.source_date_epoch.unwrap_or(u64::MAX).to_le_bytes());
+	
+	for extension in &options.store_extensions {
+		update_bytes(&mut hasher, extension.as_bytes());
+	}
+	
+	hasher.update(&(group.entries.len() as u64).to_le_bytes());
+	
+	for entry in &group.entries {
+		update_bytes(&mut hasher, entry.source.id.as_bytes());
+		update_bytes(&mut hasher, entry.path.as_os_str().as_bytes());
+		hasher.update(&entry.size.to_le_bytes());
+		
+		let modified = entry.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+		hasher.update(&modified.as_nanos().to_le_bytes());
+		
+		if let Some(part) = entry.part {
+			hasher.update(&part.offset.to_le_bytes());
+			hasher.update(&part.total_size.unwrap_or(u64::MAX).to_le_bytes());
+		}
+	}
+	
+	hasher.update(&(group.deletions.len() as u64).to_le_bytes());
+	
+	for deletion in &group.deletions {
+		update_bytes(&mut hasher, deletion.source.id.as_bytes());
+		update_bytes(&mut hasher, deletion.path.as_os_str().as_bytes());
+	}
+	
+	hasher.update(&(group.warnings.len() as u64).to_le_bytes());
+	
+	for warning in &group.warnings {
+		update_bytes(&mut hasher, warning.source().as_bytes());
+		update_bytes(&mut hasher, warning.path().as_os_str().as_bytes());
+		update_bytes(&mut hasher, warning.message().as_bytes());
+	}
+	
+	hasher.finalize()
+}
+
+fn update_bytes(hasher: &mut blake3::Hasher, bytes: &[u8]) {
+	hasher.update(&(bytes.len() as u64).to_le_bytes());
+	hasher.update(bytes);
+}
+
+/// Whether the file exists and is a complete archive which can be decrypted with the key
+fn is_complete(path: &Path, key: Key) -> Result<bool, io::Error> {
+	let mut file = match File::open(path) {
+		Ok(file) => BufReader::new(file),
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+		Err(err) => return Err(err),
+	};
+	
+	let mut header = [0u8; BKY_HEADER.len()];
+	
+	if file.read_exact(&mut header).is_err() || header != BKY_HEADER {
+		return Ok(false);
+	}
+	
+	file.seek_relative(-(BKY_HEADER.len() as i64))?;
+	
+	Ok(SubArchive::new(file, key).is_ok())
+}
+
+/// Path of the file the group at `index` is packed into
+pub(crate) fn volume_path(out: &Path, index: usize) -> PathBuf {
+	out.join(format!("{}.bky", index + 1))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::SystemTime;
+	
+	use crate::{compression::Compression, crypto::generate_key, Entry, Source};
+	
+	use super::*;
+	
+	fn group(sizes: &[u64]) -> Group {
+		let source = Source {
+			id: "source".into(),
+			is_file: false,
+			is_stdin: false,
+			path: PathBuf::from("/source").into(),
+		};
+		
+		let entries = sizes.iter()
+			.enumerate()
+			.map(|(i, &size)| Entry {
+				source: source.clone(),
+				path: PathBuf::from(format!("/source/{i}")),
+				size,
+				modified: SystemTime::UNIX_EPOCH,
+				part: None,
+			})
+			.collect();
+		
+		Group {
+			size: sizes.iter().sum(),
+			entries,
+			deletions: Vec::new(),
+			warnings: Vec::new(),
+		}
+	}
+	
+	#[test]
+	fn group_hashes_depend_on_everything_packed() {
+		let key = generate_key();
+		let options = PackOptions::default();
+		let hash = hash_group(&group(&[1, 2]), &key, &options, false);
+		
+		assert_eq!(hash_group(&group(&[1, 2]), &key, &options, false), hash);
+		assert_ne!(hash_group(&group(&[1, 3]), &key, &options, false), hash);
+		assert_ne!(hash_group(&group(&[1, 2, 0]), &key, &options, false), hash);
+		assert_ne!(hash_group(&group(&[1, 2]), &generate_key(), &options, false), hash);
+		assert_ne!(hash_group(&group(&[1, 2]), &key, &options, true), hash);
+		
+		let options = PackOptions {
+			compression: Compression::Zstd,
+			compression_level: Compression::Zstd.default_level(),
+			..PackOptions::default()
+		};
+		assert_ne!(hash_group(&group(&[1, 2]), &key, &options, false), hash);
+	}
+	
+	#[test]
+	fn state_round_trips() {
+		let dir = tempfile::tempdir().unwrap();
+		let key = generate_key();
+		let options = PackOptions::default();
+		let groups = [group(&[1]), group(&[2, 3])];
+		
+		assert_eq!(resume(dir.path(), &groups, key, &options, false).unwrap(), [false, false]);
+		assert!(has_state(dir.path()));
+		
+		let state = fs::read(dir.path().join(STATE_FILE_NAME)).unwrap();
+		let hashes: Vec<blake3::Hash> = groups.iter()
+			.map(|group| hash_group(group, &key, &options, false))
+			.collect();
+		assert_eq!(read_state(&state).unwrap(), hashes);
+		
+		assert!(read_state(&state[..state.len() - 1]).is_err());
+		assert!(read_state(&[&state[..], &[0]].concat()).is_err());
+		assert!(read_state(&state[1..]).is_err());
+		
+		finish(dir.path()).unwrap();
+		assert!(!has_state(dir.path()));
+	}
+	
+	#[test]
+	fn files_which_do_not_match_are_only_replaced_with_overwrite() {
+		let dir = tempfile::tempdir().unwrap();
+		let key = generate_key();
+		fs::write(volume_path(dir.path(), 0), b"not an archive").unwrap();
+		
+		let err = resume(dir.path(), &[group(&[1])], key, &PackOptions::default(), false).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+		
+		let options = PackOptions {
+			overwrite: true,
+			..PackOptions::default()
+		};
+		assert_eq!(resume(dir.path(), &[group(&[1])], key, &options, false).unwrap(), [false]);
+	}
+}
@@ -0,0 +1,304 @@
+use std::io::{self, Read, Seek, Write};
+
+use crate::compression::{Compression, Decoder, Encoder};
+
+/// Independently compressed part of a source group
+#[derive(Clone, Debug)]
+pub struct Segment {
+	pub compression: Compression,
+	pub compressed_size: u64,
+	pub size: u64,
+}
+
+impl Segment {
+	pub const HEADER_SIZE: usize = size_of::<u8>() + size_of::<u64>() * 2; // compression(1) + compressed_size(8) + size(8)
+	
+	pub fn write_header(&self, mut writer: impl Write) -> Result<(), io::Error> {
+		writer.write_all(&[self.compression.to_byte()])?;
+		writer.write_all(&self.compressed_size.to_le_bytes())?;
+		writer.write_all(&self.size.to_le_bytes())?;
+		
+		Ok(())
+	}
+	
+	pub fn read_header(mut reader: impl Read) -> Result<Self, io::Error> {
+		let mut buf8 = [0u8; size_of::<u8>()];
+		let mut buf64 = [0u8; size_of::<u64>()];
+		
+		reader.read_exact(&mut buf8)?;
+		let compression = Compression::from_byte(buf8[0])
+			.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unknown compression {}", buf8[0])))?;
+		
+		reader.read_exact(&mut buf64)?;
+		let compressed_size = u64::from_le_bytes(buf64);
+		
+		reader.read_exact(&mut buf64)?;
+		let size = u64::from_le_bytes(buf64);
+		
+		Ok(Self {
+			compression,
+			compressed_size,
+			size,
+		})
+	}
+}
+
+struct CountingWriter<W: Write> {
+	inner: W,
+	count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let written = self.inner.write(buf)?;
+		self.count += written as u64;
+		Ok(written)
+	}
+	
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// Writes data as a series of segments, which can each be compressed or stored
+pub struct SegmentWriter<W: Write> {
+	encoder: Option<Encoder<CountingWriter<W>>>,
+	compression: Compression,
+	level: u32,
+	threads: u32,
+	segments: Vec<Segment>,
+}
+
+impl<W: Write> SegmentWriter<W> {
+	pub fn new(inner: W, compression: Compression, level: u32, threads: u32, compressed: bool) -> Result<Self, io::Error> {
+		let mut writer = Self {
+			encoder: None,
+			compression,
+			level,
+			threads,
+			segments: Vec::new(),
+		};
+		
+		let inner = CountingWriter {
+			inner,
+			count: 0,
+		};
+		
+		writer.encoder = Some(writer.new_encoder(inner, compressed)?);
+		
+		Ok(writer)
+	}
+	
+	fn new_encoder(&self, inner: CountingWriter<W>, compressed: bool) -> Result<Encoder<CountingWriter<W>>, io::Error> {
+		let compression = if compressed {
+			self.compression
+		} else {
+			Compression::None
+		};
+		
+		Encoder::new(inner, compression, self.level, self.threads)
+	}
+	
+	fn finish_segment(&mut self) -> Result<CountingWriter<W>, io::Error> {
+		let encoder = self.encoder.take().expect("encoder should only be taken while switching segments");
+		let compression = encoder.compression();
+		let size = encoder.total_in();
+		let mut inner = encoder.finish()?;
+		
+		self.segments.push(Segment {
+			compression,
+			compressed_size: inner.count,
+			size,
+		});
+		
+		inner.count = 0;
+		Ok(inner)
+	}
+	
+	/// Compression of the current segment
+	pub fn compression(&self) -> Compression {
+		self.encoder.as_ref()
+			.expect("encoder should only be taken while switching segments")
+			.compression()
+	}
+	
+	/// Segments finished so far
+	pub fn segments(&self) -> &[Segment] {
+		&self.segments
+	}
+	
+	/// Number of bytes of the current segment written to the inner writer so far, without data buffered by the encoder
+	pub fn segment_written(&self) -> u64 {
+		self.encoder.as_ref()
+			.expect("encoder should only be taken while switching segments")
+			.get_ref()
+			.count
+	}
+	
+	/// Finishes the current segment and starts a new one
+	pub fn start_segment(&mut self, compressed: bool) -> Result<(), io::Error> {
+		let inner = self.finish_segment()?;
+		self.encoder = Some(self.new_encoder(inner, compressed)?);
+		
+		Ok(())
+	}
+	
+	pub fn finish(mut self) -> Result<(W, Vec<Segment>), io::Error> {
+		let inner = self.finish_segment()?;
+		
+		Ok((inner.inner, self.segments))
+	}
+}
+
+impl<W: Write + Seek> SegmentWriter<W> {
+	/// Discards the last finished segment and everything written after it, continuing with a new segment in its place
+	pub fn discard_last_segment(&mut self) -> Result<(), io::Error> {
+		let encoder = self.encoder.take().expect("encoder should only be taken while switching segments");
+		let mut inner = encoder.finish()?;
+		let segment = self.segments.pop().expect("a segment should have been finished");
+		
+		let discarded = inner.count + segment.compressed_size;
+		inner.inner.seek(io::SeekFrom::Current(-(discarded as i64)))?;
+		inner.count = 0;
+		
+		self.encoder = Some(Encoder::new(inner, segment.compression, self.level, self.threads)?);
+		
+		Ok(())
+	}
+}
+
+impl<W: Write> Write for SegmentWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.encoder.as_mut()
+			.expect("encoder should only be taken while switching segments")
+			.write(buf)
+	}
+	
+	fn flush(&mut self) -> io::Result<()> {
+		self.encoder.as_mut()
+			.expect("encoder should only be taken while switching segments")
+			.flush()
+	}
+}
+
+/// Reads the decompressed data of consecutive segments as one stream
+pub struct SegmentReader<'a, R: Read> {
+	reader: Option<&'a mut R>,
+	decoder: Option<Decoder<io::Take<&'a mut R>>>,
+	segments: &'a [Segment],
+}
+
+impl<'a, R: Read> SegmentReader<'a, R> {
+	pub fn new(reader: &'a mut R, segments: &'a [Segment]) -> Self {
+		Self {
+			reader: Some(reader),
+			decoder: None,
+			segments,
+		}
+	}
+}
+
+impl<R: Read> Read for SegmentReader<'_, R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		loop {
+			if let Some(decoder) = &mut self.decoder {
+				let bytes_read = decoder.read(buf)?;
+				
+				if bytes_read > 0 || buf.is_empty() {
+					return Ok(bytes_read);
+				}
+				
+				// the decoder might not have consumed all of the segment
+				let mut segment = self.decoder.take().unwrap().into_inner()?;
+				io::copy(&mut segment, &mut io::sink())?;
+				self.reader = Some(segment.into_inner());
+			}
+			
+			let Some((segment, remaining)) = self.segments.split_first() else {
+				return Ok(0);
+			};
+			
+			self.segments = remaining;
+			let reader = self.reader.take().expect("reader should be available between segments");
+			self.decoder = Some(Decoder::new(reader.take(segment.compressed_size), segment.compression)?);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+	
+	use super::*;
+	
+	fn write_segments(writer: &mut SegmentWriter<impl Write>, segments: &[(&[u8], bool)]) {
+		for (i, &(data, compressed)) in segments.iter().enumerate() {
+			if i > 0 {
+				writer.start_segment(compressed).unwrap();
+			}
+			
+			writer.write_all(data).unwrap();
+		}
+	}
+	
+	fn read_segments(data: &[u8], segments: &[Segment]) -> Vec<u8> {
+		let mut reader = Cursor::new(data);
+		let mut read = Vec::new();
+		SegmentReader::new(&mut reader, segments).read_to_end(&mut read).unwrap();
+		read
+	}
+	
+	#[test]
+	fn segments_round_trip() {
+		let compressible = vec![b'a'; 100_000];
+		let mut writer = SegmentWriter::new(Vec::new(), Compression::Zstd, Compression::Zstd.default_level(), 0, true).unwrap();
+		write_segments(&mut writer, &[(&compressible, true), (b"stored", false), (b"", true), (&compressible, true)]);
+		let (data, segments) = writer.finish().unwrap();
+		
+		let compressions: Vec<Compression> = segments.iter().map(|segment| segment.compression).collect();
+		assert_eq!(compressions, [Compression::Zstd, Compression::None, Compression::Zstd, Compression::Zstd]);
+		
+		let sizes: Vec<u64> = segments.iter().map(|segment| segment.size).collect();
+		assert_eq!(sizes, [100_000, 6, 0, 100_000]);
+		assert_eq!(segments[1].compressed_size, 6);
+		assert!(segments[0].compressed_size < 1000);
+		assert_eq!(segments.iter().map(|segment| segment.compressed_size).sum::<u64>(), data.len() as u64);
+		
+		let expected = [&compressible[..], b"stored", &compressible[..]].concat();
+		assert_eq!(read_segments(&data, &segments), expected);
+	}
+	
+	#[test]
+	fn segment_headers_round_trip() {
+		let segment = Segment {
+			compression: Compression::Lz4,
+			compressed_size: 1 << 40,
+			size: u64::MAX,
+		};
+		
+		let mut header = Vec::new();
+		segment.write_header(&mut header).unwrap();
+		assert_eq!(header.len(), Segment::HEADER_SIZE);
+		
+		let read = Segment::read_header(&header[..]).unwrap();
+		assert_eq!(read.compression, Compression::Lz4);
+		assert_eq!(read.compressed_size, 1 << 40);
+		assert_eq!(read.size, u64::MAX);
+		
+		header[0] = 0xff;
+		assert_eq!(Segment::read_header(&header[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+	}
+	
+	#[test]
+	fn discarded_segments_are_overwritten() {
+		let mut writer = SegmentWriter::new(Cursor::new(Vec::new()), Compression::Zstd, Compression::Zstd.default_level(), 0, true).unwrap();
+		write_segments(&mut writer, &[(b"kept", false), (b"discarded", true), (b"partially written", false)]);
+		writer.discard_last_segment().unwrap();
+		writer.write_all(b"written again").unwrap();
+		let (data, segments) = writer.finish().unwrap();
+		
+		assert_eq!(segments.len(), 2);
+		assert_eq!(segments[1].compression, Compression::Zstd);
+		assert_eq!(read_segments(data.get_ref(), &segments), b"keptwritten again");
+	}
+}
@@ -0,0 +1,78 @@
+use std::{fmt, io, path::{Path, PathBuf}};
+
+use crate::Source;
+
+/// File which couldn't be packed completely or changed while it was read, which is recorded in the archive
+#[derive(Clone, Debug)]
+pub struct Warning {
+	pub(crate) source: Source,
+	/// Path of the file relative to its source, like the paths of the entries in the archive
+	pub(crate) path: PathBuf,
+	pub(crate) message: String,
+}
+
+impl Warning {
+	pub(crate) fn new(source: Source, path: PathBuf, message: String) -> Self {
+		Self {
+			source,
+			path,
+			message,
+		}
+	}
+	
+	/// Turns an error about the file at `path` into a warning if errors are skipped, otherwise returns the error
+	pub(crate) fn from_error(err: io::Error, source: &Source, path: &Path, continue_on_error: bool) -> Result<Self, io::Error> {
+		if !continue_on_error {
+			return Err(err);
+		}
+		
+		Ok(Self::for_file(source, path, err.to_string()))
+	}
+	
+	/// Creates a warning about the file at the absolute path `path` below the source
+	pub(crate) fn for_file(source: &Source, path: &Path, message: String) -> Self {
+		let prefix = if source.is_file {
+			source.path.parent().unwrap_or(Path::new(""))
+		} else {
+			&*source.path
+		};
+		
+		Self::new(source.clone(), path.strip_prefix(prefix).unwrap_or(path).to_owned(), message)
+	}
+	
+	/// Name of the source containing the file
+	pub fn source(&self) -> &str {
+		&self.source.id
+	}
+	
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+	
+	pub fn message(&self) -> &str {
+		&self.message
+	}
+}
+
+impl fmt::Display for Warning {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.source.is_file {
+			write!(f, "{}: {}", self.path.to_string_lossy(), self.message)
+		} else {
+			write!(f, "{}/{}: {}", self.source.id, self.path.to_string_lossy(), self.message)
+		}
+	}
+}
+
+/// Prints the files which couldn't be packed completely or changed while they were read after packing
+pub(crate) fn print_summary(warnings: &[Warning]) {
+	if warnings.is_empty() {
+		return;
+	}
+	
+	eprintln!("{} files couldn't be packed completely or changed while they were read:", warnings.len());
+	
+	for warning in warnings {
+		eprintln!("  {warning}");
+	}
+}